@@ -0,0 +1,484 @@
+use crate::journal::{self, JournalEvent};
+use crate::recorder::TakeSink;
+use crate::session::{RecordingSession, SplitPolicy, KEEPER_SUFFIX};
+use chrono::{DateTime, Datelike, Local};
+use midly::live::LiveEvent;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+/// Archive directories where takes are saved.
+/// While the archive is not writable, the take is kept in memory and saving is retried.
+pub struct Archive {
+    directories: Vec<PathBuf>,
+    retry_delay: Duration,
+    retry_at: Option<Instant>,
+    /// File of the take saved last, for control actions on it.
+    last_saved: Option<PathBuf>,
+}
+
+impl Archive {
+    const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+    pub fn new(directory: PathBuf, fallback: Option<PathBuf>) -> Self {
+        Archive {
+            directories: std::iter::once(directory).chain(fallback).collect(),
+            retry_delay: Self::MIN_RETRY_DELAY,
+            retry_at: None,
+            last_saved: None,
+        }
+    }
+}
+
+impl TakeSink for Archive {
+    fn retry_now(&mut self) {
+        self.retry_delay = Self::MIN_RETRY_DELAY;
+        self.retry_at = None;
+    }
+
+    fn is_ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|t| now >= t)
+    }
+
+    /// Saves the take into the first directory that accepts it, returns false if none did.
+    fn save(&mut self, session: &mut RecordingSession, now: Instant) -> bool {
+        let file_time = session.clock().wall_time();
+        for (i, directory) in self.directories.iter().enumerate() {
+            match save_to_directory(session, directory, file_time) {
+                Ok(path) => {
+                    // A take dropped for being short leaves the last saved one.
+                    if let Some(path) = path {
+                        self.last_saved = Some(path);
+                    }
+                    if i > 0 {
+                        eprintln!("Warning: take saved to fallback directory.");
+                    }
+                    self.retry_now();
+                    return true;
+                }
+                Err(e) => eprintln!(
+                    "Warning: cannot save take to {}: {}",
+                    directory.display(),
+                    e
+                ),
+            }
+        }
+        eprintln!(
+            "Warning: take is kept in memory, retrying in {} s.",
+            self.retry_delay.as_secs()
+        );
+        self.retry_at = Some(now + self.retry_delay);
+        self.retry_delay = (self.retry_delay * 2).min(Self::MAX_RETRY_DELAY);
+        false
+    }
+
+    fn discard_last(&mut self) -> bool {
+        let Some(path) = self.last_saved.take() else {
+            return false;
+        };
+        match fs::remove_file(&path) {
+            Ok(()) => println!("Removed {}", path.display()),
+            Err(e) => eprintln!("Warning: cannot remove {}: {}", path.display(), e),
+        }
+        true
+    }
+
+    fn keep_last(&mut self) -> bool {
+        let Some(path) = &self.last_saved else {
+            return false;
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if stem.ends_with(KEEPER_SUFFIX) {
+            return true;
+        }
+        let keeper = path.with_file_name(format!("{}{}.mid", stem, KEEPER_SUFFIX));
+        match fs::rename(path, &keeper) {
+            Ok(()) => {
+                println!("Renamed to {}", keeper.display());
+                self.last_saved = Some(keeper);
+            }
+            Err(e) => eprintln!("Warning: cannot rename {}: {}", path.display(), e),
+        }
+        true
+    }
+}
+
+/// Saves the take into a subdirectory of the archive per day.
+/// Returns the path of the file, None if the take was not written.
+pub fn save_to_directory(
+    session: &mut RecordingSession,
+    directory: &Path,
+    file_time: DateTime<Local>,
+) -> io::Result<Option<PathBuf>> {
+    let mut saved = None;
+    session.save_take(file_time, |file_name, data| {
+        let file_path = target_directory(directory, file_time)?.join(file_name);
+        println!("\nWriting recording to {:}", &file_path.display());
+        write_file_atomically(&file_path, data)?;
+        saved = Some(file_path);
+        Ok(())
+    })?;
+    Ok(saved)
+}
+
+fn target_directory(base_path: &Path, time: DateTime<Local>) -> io::Result<PathBuf> {
+    let directory = base_path
+        .join(time.year().to_string())
+        .join(time.month().to_string())
+        .join(time.day().to_string());
+
+    fs::create_dir_all(&directory)?;
+
+    if !directory.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("Path exists but is not a directory {}", directory.display()),
+        ));
+    }
+    Ok(directory)
+}
+
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// Writes the file so it is either complete or absent even if the system crashes meanwhile:
+/// data goes to a hidden temporary file that is linked into place once it is on the disk.
+/// An existing file is never replaced.
+pub fn write_file_atomically(file_path: &Path, data: &[u8]) -> io::Result<()> {
+    let directory = file_path.parent().unwrap_or(Path::new("."));
+    let file_name = file_path
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "No file name"))?;
+    let temp_path = directory.join(format!(
+        ".{}{}",
+        file_name.to_string_lossy(),
+        TEMP_FILE_SUFFIX
+    ));
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| link_new_file(&temp_path, file_path));
+    // Do not leave partial data behind, e.g. when the disk is full.
+    let removed = fs::remove_file(&temp_path);
+    result?;
+    if let Err(e) = removed.or_else(|e| match e.kind() {
+        // Renamed into place.
+        ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }) {
+        eprintln!("Warning: cannot remove {}: {}", temp_path.display(), e);
+    }
+    // Persist the link. The file is saved already, so a failure is only reported.
+    if let Err(e) = File::open(directory).and_then(|d| d.sync_all()) {
+        eprintln!(
+            "Warning: cannot sync directory {}: {}",
+            directory.display(),
+            e
+        );
+    }
+    Ok(())
+}
+
+/// Links the written file under its final name, fails if that file exists.
+fn link_new_file(temp_path: &Path, file_path: &Path) -> io::Result<()> {
+    let exists = || {
+        io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("File already exists {}", file_path.display()),
+        )
+    };
+    // Unlike a rename, linking fails if the file exists.
+    match fs::hard_link(temp_path, file_path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(exists()),
+        // File systems such as FAT have no links, there the check is not atomic.
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::PermissionDenied | ErrorKind::Unsupported
+            ) =>
+        {
+            if file_path.exists() {
+                return Err(exists());
+            }
+            fs::rename(temp_path, file_path)
+        }
+        result => result,
+    }
+}
+
+/// Deletes temporary files left when writing was interrupted, the complete take is
+/// recovered from its journal instead.
+pub fn remove_stale_temp_files(directory: &Path) -> io::Result<()> {
+    if !directory.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
+            remove_stale_temp_files(&path)?;
+        } else if name.starts_with('.') && name.ends_with(TEMP_FILE_SUFFIX) {
+            // A recent one may be being written by another recorder.
+            let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if age > Duration::from_secs(60) {
+                println!("Removing incomplete file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Saves takes from journals left by a recorder that did not finish properly.
+pub fn recover_journals(archive_directory: &Path) -> io::Result<()> {
+    for path in journal::find_abandoned(archive_directory)? {
+        println!("Recovering take from {}", path.display());
+        let contents = journal::read(&path)?;
+        let mut session = RecordingSession::new(SplitPolicy::default(), contents.track_names);
+        for (track, timestamp, event) in &contents.events {
+            match event {
+                JournalEvent::Midi(bytes) => {
+                    if let Ok(event) = LiveEvent::parse(bytes) {
+                        session.add_event(*track, event, *timestamp);
+                    }
+                }
+                JournalEvent::Keep => session.mark_keeper(*track, *timestamp),
+                JournalEvent::Clock(clock) => session.resume_clock(clock.clone()),
+            }
+        }
+        let file_time = contents.start_time.unwrap_or_else(chrono::Local::now);
+        save_to_directory(&mut session, archive_directory, file_time)?;
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PACKAGE_NAME;
+    use midly::live::SystemRealtime;
+    use midly::num::{u4, u7};
+    use midly::{Arena, MidiMessage, Smf, TrackEventKind};
+
+    fn new_session() -> RecordingSession {
+        RecordingSession::new(SplitPolicy::default(), vec!["Test port".to_string()])
+    }
+
+    fn note_on(key: u8) -> LiveEvent<'static> {
+        LiveEvent::Midi {
+            channel: u4::from(0),
+            message: MidiMessage::NoteOn {
+                key: u7::from(key),
+                vel: u7::from(64),
+            },
+        }
+    }
+
+    fn note_off(key: u8) -> LiveEvent<'static> {
+        LiveEvent::Midi {
+            channel: u4::from(0),
+            message: MidiMessage::NoteOff {
+                key: u7::from(key),
+                vel: u7::from(0),
+            },
+        }
+    }
+
+    fn archived_files(directory: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut pending = vec![directory.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn take_is_recovered_from_journal() {
+        let directory = std::env::temp_dir().join(format!(
+            "{}-test-journal-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        let mut session = new_session().with_journal(directory.clone());
+        session.add_event(0, note_on(60), 1_000);
+        session.add_event(
+            0,
+            LiveEvent::parse(&[0xF0, 0x41, 0x10, 0x42, 0xF7]).unwrap(),
+            2_000,
+        );
+        session.sync_journal();
+        drop(session); // Crash before saving.
+
+        recover_journals(&directory).unwrap();
+        assert!(journal::find_abandoned(&directory).unwrap().is_empty());
+        let files = archived_files(&directory);
+        assert_eq!(files.len(), 1);
+        let data = fs::read(&files[0]).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+        let kinds: Vec<_> = smf.tracks[0].iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TrackEventKind::Meta(midly::MetaMessage::TrackName(b"Test port")),
+                note_on(60).as_track_event(&Arena::new()),
+                TrackEventKind::SysEx(&[0x41, 0x10, 0x42, 0xF7]),
+                note_off(60).as_track_event(&Arena::new()),
+                TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            ]
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn recovered_take_follows_midi_clock() {
+        let directory = std::env::temp_dir().join(format!(
+            "{}-test-journal-clock-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        let clock = LiveEvent::Realtime(SystemRealtime::TimingClock);
+        let mut session = new_session().with_journal(directory.clone());
+        // 100 BPM, the take starts within the second beat.
+        for pulse in 0..30 {
+            session.add_event(0, clock, pulse * 25_000);
+        }
+        session.add_event(0, note_on(60), 30 * 25_000 + 5_000);
+        session.set_track_name(0, "Drum machine".to_string());
+        for pulse in 30..80 {
+            session.add_event(0, clock, pulse * 25_000);
+        }
+        session.add_event(0, note_off(60), 70 * 25_000);
+        let expected = format!("{:?}", session.tracks());
+        session.sync_journal();
+        drop(session); // Crash before saving.
+
+        recover_journals(&directory).unwrap();
+        let files = archived_files(&directory);
+        assert_eq!(files.len(), 1);
+        let data = fs::read(&files[0]).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+        assert!(smf.tracks[0]
+            .iter()
+            .any(|e| e.kind == TrackEventKind::Meta(midly::MetaMessage::Tempo(600_000.into()))));
+        assert_eq!(format!("{:?}", smf.tracks), expected);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn recovered_take_stays_keeper() {
+        let directory = std::env::temp_dir().join(format!(
+            "{}-test-journal-keep-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        let mut session = new_session().with_journal(directory.clone());
+        session.add_event(0, note_on(60), 1_000);
+        session.mark_keeper(0, 2_000);
+        session.add_event(0, note_off(60), 3_000);
+        session.sync_journal();
+        drop(session); // Crash before saving.
+
+        recover_journals(&directory).unwrap();
+        let files = archived_files(&directory);
+        assert_eq!(files.len(), 1);
+        let stem = files[0].file_stem().unwrap().to_string_lossy().into_owned();
+        assert!(stem.ends_with(KEEPER_SUFFIX), "{}", stem);
+        let data = fs::read(&files[0]).unwrap();
+        let smf = Smf::parse(&data).unwrap();
+        assert!(smf.tracks[0]
+            .iter()
+            .any(|e| e.kind == TrackEventKind::Meta(midly::MetaMessage::Marker(b"Keep"))));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn short_take_leaves_last_saved() {
+        let directory = std::env::temp_dir().join(format!(
+            "{}-test-last-saved-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        let policy = SplitPolicy {
+            min_events: 2,
+            ..SplitPolicy::default()
+        };
+        let mut session = RecordingSession::new(policy, vec!["Test port".to_string()]);
+        let mut archive = Archive::new(directory.clone(), None);
+        session.add_event(0, note_on(60), 1_000);
+        session.add_event(0, note_off(60), 2_000);
+        assert!(archive.save(&mut session, Instant::now()));
+        // Dropped for having too few events.
+        session.add_event(0, note_on(62), 3_000);
+        assert!(archive.save(&mut session, Instant::now()));
+        assert!(archive.keep_last());
+        let files = archived_files(&directory);
+        assert_eq!(files.len(), 1);
+        let stem = files[0].file_stem().unwrap().to_string_lossy().into_owned();
+        assert!(stem.ends_with(KEEPER_SUFFIX), "{}", stem);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn archive_falls_back_when_not_writable() {
+        let directory = std::env::temp_dir().join(format!(
+            "{}-test-fallback-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        // A regular file cannot be used as the archive directory.
+        let broken = directory.join("not-a-directory");
+        fs::write(&broken, b"").unwrap();
+        let fallback = directory.join("fallback");
+
+        let mut session = new_session();
+        session.add_event(0, note_on(60), 1_000);
+        let now = Instant::now();
+        let mut archive = Archive::new(broken.clone(), None);
+        assert!(!archive.save(&mut session, now));
+        assert!(!archive.is_ready(now));
+        assert_eq!(session.event_count(), 1);
+
+        let mut archive = Archive::new(broken, Some(fallback.clone()));
+        assert!(archive.save(&mut session, now));
+        assert_eq!(session.event_count(), 0);
+        assert!(fallback.is_dir());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn existing_file_is_not_replaced() {
+        let directory = std::env::temp_dir().join(format!(
+            "{}-test-atomic-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("take.mid");
+        write_file_atomically(&path, b"first").unwrap();
+        let error = write_file_atomically(&path, b"second").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        // No temporary file is left behind.
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
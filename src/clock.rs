@@ -0,0 +1,62 @@
+use chrono::{DateTime, Local};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of the current time, so recording can be driven by simulated time.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, used to detect pauses and long takes.
+    fn now(&self) -> Instant;
+
+    /// Calendar time, used to name files.
+    fn wall_time(&self) -> DateTime<Local>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_time(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// Clock that only moves when advanced. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_wall_time: DateTime<Local>,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new(start_wall_time: DateTime<Local>) -> Self {
+        ManualClock {
+            start: Instant::now(),
+            start_wall_time,
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn wall_time(&self) -> DateTime<Local> {
+        self.start_wall_time + self.elapsed()
+    }
+}
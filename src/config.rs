@@ -0,0 +1,137 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+/// Recording options. Unset values are taken from a less specific source:
+/// command line, then profile, then top level of the configuration file, then defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    pub archive_dir: Option<PathBuf>,
+    pub fallback_dir: Option<PathBuf>,
+    pub ports: Option<Vec<String>>,
+    pub all_ports: Option<bool>,
+    pub virtual_port: Option<String>,
+    pub jack: Option<bool>,
+    pub rtp_midi_port: Option<u16>,
+    pub tcp_midi_port: Option<u16>,
+    pub ipmidi_port: Option<u16>,
+    pub osc_port: Option<u16>,
+    pub osc_mappings: Option<Vec<String>>,
+    pub wait_for_port: Option<bool>,
+    pub record_timecode: Option<bool>,
+    pub split_after: Option<u64>,
+    pub max_take_length: Option<u64>,
+    pub split_while_held: Option<bool>,
+    pub follow_transport: Option<bool>,
+    pub controls: Option<Vec<String>>,
+    pub filter_controls: Option<bool>,
+    pub min_events: Option<usize>,
+}
+
+impl Options {
+    /// Fills values that are not set here from the other options.
+    pub fn or(self, other: Options) -> Options {
+        Options {
+            archive_dir: self.archive_dir.or(other.archive_dir),
+            fallback_dir: self.fallback_dir.or(other.fallback_dir),
+            ports: self.ports.or(other.ports),
+            all_ports: self.all_ports.or(other.all_ports),
+            virtual_port: self.virtual_port.or(other.virtual_port),
+            jack: self.jack.or(other.jack),
+            rtp_midi_port: self.rtp_midi_port.or(other.rtp_midi_port),
+            tcp_midi_port: self.tcp_midi_port.or(other.tcp_midi_port),
+            ipmidi_port: self.ipmidi_port.or(other.ipmidi_port),
+            osc_port: self.osc_port.or(other.osc_port),
+            osc_mappings: self.osc_mappings.or(other.osc_mappings),
+            wait_for_port: self.wait_for_port.or(other.wait_for_port),
+            record_timecode: self.record_timecode.or(other.record_timecode),
+            split_after: self.split_after.or(other.split_after),
+            max_take_length: self.max_take_length.or(other.max_take_length),
+            split_while_held: self.split_while_held.or(other.split_while_held),
+            follow_transport: self.follow_transport.or(other.follow_transport),
+            controls: self.controls.or(other.controls),
+            filter_controls: self.filter_controls.or(other.filter_controls),
+            min_events: self.min_events.or(other.min_events),
+        }
+    }
+}
+
+/// Contents of the configuration file.
+///
+/// ```toml
+/// archive_dir = "/home/pi/midi-archive"
+///
+/// [profiles.piano]
+/// ports = ["Digital Piano"]
+/// split_after = 30
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    pub defaults: Options,
+    pub profiles: BTreeMap<String, Options>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        // Top level values are the defaults, profiles are tables.
+        let mut table: toml::Table = toml::from_str(text)?;
+        let profiles = table.remove("profiles");
+        let mut config = Config {
+            defaults: table.try_into()?,
+            profiles: BTreeMap::new(),
+        };
+        if let Some(profiles) = profiles {
+            config.profiles = profiles.try_into()?;
+        }
+        Ok(config)
+    }
+
+    /// Reads the given file, or the file at the default location if there is one.
+    pub fn load(path: Option<&Path>) -> io::Result<Config> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path().filter(|p| p.is_file()) {
+                Some(path) => path,
+                None => return Ok(Config::default()),
+            },
+        };
+        let text = fs::read_to_string(&path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e))
+        })?;
+        Config::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid configuration {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Options of the profile, with defaults from the top level of the file.
+    pub fn options(&self, profile: Option<&str>) -> io::Result<Options> {
+        let profile_options = match profile {
+            Some(name) => self.profiles.get(name).cloned().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No profile '{}' in the configuration", name),
+                )
+            })?,
+            None => Options::default(),
+        };
+        Ok(profile_options.or(self.defaults.clone()))
+    }
+}
+
+/// User configuration directory, `$XDG_CONFIG_HOME` or `~/.config` if it is not set or empty.
+pub fn config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+}
+
+/// `$XDG_CONFIG_HOME/midi-blackbox/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    config_home().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+}
@@ -0,0 +1,384 @@
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Controller values from this one on count as pressed, as with sustain pedals.
+const PRESSED_VALUE: u8 = 64;
+
+/// What a control binding does to the take in progress, or to the last saved take
+/// when none is in progress.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlAction {
+    /// Save the take now.
+    Split,
+    /// Drop the take.
+    Discard,
+    /// Mark the take as a keeper.
+    Keep,
+}
+
+/// MIDI input that triggers a control action, on any channel.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlTrigger {
+    /// Controller going past the middle, e.g. a footswitch.
+    Controller(u8),
+    /// Keys that are held down together, or a single key.
+    Keys(Vec<u8>),
+}
+
+/// Binds a control action to MIDI input. Written as the action, `split`, `discard` or
+/// `keep`, and the trigger: `cc` with a controller number or `note` with keys held together,
+/// e.g. `split=cc80` or `discard=note21+22+23`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlBinding {
+    pub action: ControlAction,
+    pub trigger: ControlTrigger,
+}
+
+impl FromStr for ControlBinding {
+    type Err = String;
+
+    fn from_str(binding: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid control '{}', expected e.g. 'split=cc80' or 'discard=note21+22'",
+                binding
+            )
+        };
+        let (action, trigger) = binding.split_once('=').ok_or_else(invalid)?;
+        let action = match action.trim() {
+            "split" => ControlAction::Split,
+            "discard" => ControlAction::Discard,
+            "keep" => ControlAction::Keep,
+            _ => return Err(format!("Unknown action in control '{}'", binding)),
+        };
+        let number = |text: &str| match text.trim().parse::<u8>() {
+            Ok(number) if number < 128 => Ok(number),
+            _ => Err(invalid()),
+        };
+        let trigger = trigger.trim();
+        let trigger = if let Some(controller) = trigger.strip_prefix("cc") {
+            ControlTrigger::Controller(number(controller)?)
+        } else if let Some(keys) = trigger.strip_prefix("note") {
+            ControlTrigger::Keys(keys.split('+').map(number).collect::<Result<_, _>>()?)
+        } else {
+            return Err(invalid());
+        };
+        Ok(ControlBinding { action, trigger })
+    }
+}
+
+/// What becomes of an event that went through the controls.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Handled {
+    pub(crate) action: Option<ControlAction>,
+    /// Key presses that were held back and are recorded before the event, with timestamps.
+    pub(crate) released: Vec<(LiveEvent<'static>, u64)>,
+    /// Whether the event itself is recorded.
+    pub(crate) record: bool,
+    /// The event pressed or released a control that was triggered, it never starts a take.
+    pub(crate) control: bool,
+}
+
+/// Control bindings with the state of their controllers and keys.
+pub struct Controls {
+    bindings: Vec<ControlBinding>,
+    /// Whether events of bound controllers, and of keys of combinations that were played,
+    /// are left out of the recording.
+    filter: bool,
+    /// Bound controllers that are pressed and bound keys that are held, per port.
+    pressed_controllers: HashSet<(usize, u8)>,
+    held_keys: HashSet<(usize, u8)>,
+    /// Presses of bound keys held back until it is known whether they complete a combination,
+    /// as port, event and timestamp.
+    pending: Vec<(usize, LiveEvent<'static>, u64)>,
+    /// Keys of combinations that were played whose presses were left out, so are their releases.
+    consumed: HashSet<(usize, u8)>,
+    /// Keys of combinations that were played and are still held.
+    fired: HashSet<(usize, u8)>,
+}
+
+impl Controls {
+    pub fn new(bindings: Vec<ControlBinding>, filter: bool) -> Self {
+        Controls {
+            bindings,
+            filter,
+            pressed_controllers: HashSet::new(),
+            held_keys: HashSet::new(),
+            pending: Vec::new(),
+            consumed: HashSet::new(),
+            fired: HashSet::new(),
+        }
+    }
+
+    fn is_bound_key(&self, key: u8) -> bool {
+        self.bindings
+            .iter()
+            .any(|b| matches!(&b.trigger, ControlTrigger::Keys(keys) if keys.contains(&key)))
+    }
+
+    /// Takes the held back key presses of the port, they are recorded after all.
+    fn release_pending(&mut self, port: usize) -> Vec<(LiveEvent<'static>, u64)> {
+        let (released, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| p.0 == port);
+        self.pending = pending;
+        released
+            .into_iter()
+            .map(|(_, event, t)| (event, t))
+            .collect()
+    }
+
+    /// Returns the action that the event triggers and what is recorded. While filtering or
+    /// `idle`, with no take in progress, presses of bound keys are held back until a combination
+    /// is complete, another channel message arrives from the port, or a key is released.
+    pub(crate) fn handle(
+        &mut self,
+        port: usize,
+        event: &LiveEvent,
+        timestamp: u64,
+        idle: bool,
+    ) -> Handled {
+        let mut handled = Handled {
+            action: None,
+            released: Vec::new(),
+            record: true,
+            control: false,
+        };
+        // Clock and other system messages keep running while the keys are played.
+        let LiveEvent::Midi { channel, message } = *event else {
+            return handled;
+        };
+        match message {
+            MidiMessage::Controller { controller, value } => {
+                handled.released = self.release_pending(port);
+                let controller = controller.as_int();
+                let Some(binding) = self
+                    .bindings
+                    .iter()
+                    .find(|b| b.trigger == ControlTrigger::Controller(controller))
+                else {
+                    return handled;
+                };
+                let action = binding.action;
+                let pressed = if value.as_int() >= PRESSED_VALUE {
+                    self.pressed_controllers.insert((port, controller))
+                } else {
+                    self.pressed_controllers.remove(&(port, controller));
+                    false
+                };
+                handled.action = pressed.then_some(action);
+                handled.record = !self.filter;
+                handled.control = true;
+            }
+            MidiMessage::NoteOn { key, vel } if vel > 0 && self.is_bound_key(key.as_int()) => {
+                let key = key.as_int();
+                self.held_keys.insert((port, key));
+                // The key that completes the combination triggers it.
+                let combination = self.bindings.iter().find_map(|b| match &b.trigger {
+                    ControlTrigger::Keys(keys)
+                        if keys.contains(&key)
+                            && keys.iter().all(|k| self.held_keys.contains(&(port, *k))) =>
+                    {
+                        Some((b.action, keys.clone()))
+                    }
+                    _ => None,
+                });
+                let hold_back = self.filter || idle;
+                let Some((action, keys)) = combination else {
+                    if hold_back {
+                        self.pending.push((port, event.to_static(), timestamp));
+                        handled.record = false;
+                    }
+                    return handled;
+                };
+                handled.action = Some(action);
+                handled.control = true;
+                self.fired.extend(
+                    keys.iter()
+                        .map(|k| (port, *k))
+                        .filter(|k| self.held_keys.contains(k)),
+                );
+                if !hold_back {
+                    return handled;
+                }
+                handled.record = false;
+                self.consumed.insert((port, key));
+                // Presses of the combination are left out, others are still undecided.
+                let bound_press = |p: &(usize, LiveEvent, u64)| match p.1 {
+                    LiveEvent::Midi {
+                        channel: c,
+                        message: MidiMessage::NoteOn { key: k, .. },
+                    } => p.0 == port && c == channel && keys.contains(&k.as_int()),
+                    _ => false,
+                };
+                for (_, event, _) in self.pending.iter().filter(|p| bound_press(p)) {
+                    if let LiveEvent::Midi {
+                        message: MidiMessage::NoteOn { key, .. },
+                        ..
+                    } = event
+                    {
+                        self.consumed.insert((port, key.as_int()));
+                    }
+                }
+                self.pending.retain(|p| !bound_press(p));
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. }
+                if self.is_bound_key(key.as_int()) =>
+            {
+                let key = key.as_int();
+                self.held_keys.remove(&(port, key));
+                handled.control = self.fired.remove(&(port, key));
+                if self.consumed.remove(&(port, key)) {
+                    handled.record = false;
+                } else {
+                    handled.released = self.release_pending(port);
+                }
+            }
+            _ => handled.released = self.release_pending(port),
+        }
+        handled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u4, u7};
+
+    fn midi(message: MidiMessage) -> LiveEvent<'static> {
+        LiveEvent::Midi {
+            channel: u4::from(0),
+            message,
+        }
+    }
+
+    fn control(controller: u8, value: u8) -> LiveEvent<'static> {
+        midi(MidiMessage::Controller {
+            controller: u7::from(controller),
+            value: u7::from(value),
+        })
+    }
+
+    fn note_on(key: u8) -> LiveEvent<'static> {
+        midi(MidiMessage::NoteOn {
+            key: u7::from(key),
+            vel: u7::from(100),
+        })
+    }
+
+    fn note_off(key: u8) -> LiveEvent<'static> {
+        midi(MidiMessage::NoteOff {
+            key: u7::from(key),
+            vel: u7::from(0),
+        })
+    }
+
+    #[test]
+    fn bindings_are_parsed() {
+        assert_eq!(
+            "split=cc80".parse(),
+            Ok(ControlBinding {
+                action: ControlAction::Split,
+                trigger: ControlTrigger::Controller(80),
+            })
+        );
+        assert_eq!(
+            "discard=note21+22+23".parse(),
+            Ok(ControlBinding {
+                action: ControlAction::Discard,
+                trigger: ControlTrigger::Keys(vec![21, 22, 23]),
+            })
+        );
+        for invalid in [
+            "keep",
+            "keep=cc128",
+            "keep=note21+",
+            "save=cc80",
+            "keep=pc1",
+        ] {
+            assert!(invalid.parse::<ControlBinding>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn controls_trigger_once_per_press() {
+        let bindings = ["split=cc80", "discard=note21+22"]
+            .iter()
+            .map(|b| b.parse().unwrap())
+            .collect();
+        let mut controls = Controls::new(bindings, true);
+        let mut handle = |port, event| {
+            let handled = controls.handle(port, &event, 0, false);
+            (handled.action, handled.record)
+        };
+        let split = Some(ControlAction::Split);
+        let discard = Some(ControlAction::Discard);
+        assert_eq!(handle(0, control(80, 127)), (split, false));
+        assert_eq!(handle(0, control(80, 100)), (None, false));
+        assert_eq!(handle(0, control(80, 0)), (None, false));
+        assert_eq!(handle(0, control(80, 127)), (split, false));
+        assert_eq!(handle(0, control(64, 127)), (None, true));
+
+        assert_eq!(handle(0, note_on(21)), (None, false));
+        // Keys of another input do not count.
+        assert_eq!(handle(1, note_on(22)), (None, false));
+        assert_eq!(handle(0, note_on(22)), (discard, false));
+        assert_eq!(handle(0, note_off(21)), (None, false));
+        assert_eq!(handle(0, note_on(21)), (discard, false));
+        assert_eq!(handle(0, note_off(21)), (None, false));
+        assert_eq!(handle(0, note_off(22)), (None, false));
+    }
+
+    #[test]
+    fn bound_keys_are_recorded_unless_combination_is_played() {
+        let bindings = vec!["discard=note21+22+23".parse().unwrap()];
+        let mut controls = Controls::new(bindings, true);
+        // A lone bass note is held back until it is released.
+        let handled = controls.handle(0, &note_on(21), 1_000, false);
+        assert_eq!((handled.record, handled.released.len()), (false, 0));
+        let handled = controls.handle(0, &note_off(21), 2_000, false);
+        assert!(handled.record);
+        assert_eq!(handled.released, vec![(note_on(21), 1_000)]);
+
+        // Or until something else is played.
+        controls.handle(0, &note_on(22), 3_000, false);
+        let handled = controls.handle(0, &note_on(60), 4_000, false);
+        assert!(handled.record);
+        assert_eq!(handled.released, vec![(note_on(22), 3_000)]);
+        assert!(controls.handle(0, &note_off(22), 5_000, false).record);
+
+        // The whole combination is left out.
+        controls.handle(0, &note_on(21), 6_000, false);
+        controls.handle(0, &note_on(22), 6_000, false);
+        let handled = controls.handle(0, &note_on(23), 6_000, false);
+        assert_eq!(handled.action, Some(ControlAction::Discard));
+        assert_eq!((handled.record, handled.released.len()), (false, 0));
+        for key in [21, 22, 23] {
+            let handled = controls.handle(0, &note_off(key), 7_000, false);
+            assert_eq!((handled.record, handled.released.len()), (false, 0));
+        }
+    }
+
+    #[test]
+    fn combination_without_take_is_not_recorded() {
+        let bindings = vec!["keep=note21+22".parse().unwrap()];
+        let mut controls = Controls::new(bindings, false);
+        let handled = controls.handle(0, &note_on(21), 1_000, true);
+        assert!(!handled.record);
+        let handled = controls.handle(0, &note_on(22), 1_000, true);
+        assert_eq!(handled.action, Some(ControlAction::Keep));
+        assert!(!handled.record);
+        for key in [21, 22] {
+            let handled = controls.handle(0, &note_off(key), 2_000, true);
+            assert!(!handled.record || handled.control);
+            assert!(handled.released.is_empty());
+        }
+        // Without a combination, a bound key is played as usual.
+        assert!(!controls.handle(0, &note_on(21), 3_000, true).record);
+        let handled = controls.handle(0, &note_off(21), 4_000, true);
+        assert_eq!(handled.released, vec![(note_on(21), 3_000)]);
+        assert!(handled.record && !handled.control);
+    }
+}
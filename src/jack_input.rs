@@ -0,0 +1,201 @@
+use crate::ports::{record_message, PortSelection};
+use crate::recorder::{MidiSource, SharedSession};
+use crate::session::SysExAssembler;
+use crate::PACKAGE_NAME;
+use jack::contrib::ClosureProcessHandler;
+use jack::{AsyncClient, Client, ClientOptions, ClientStatus, Control, NotificationHandler};
+use jack::{MidiIn, Port, PortFlags, ProcessScope, Unowned};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Messages that can wait in the queue between the process callback and the session.
+const QUEUE_LENGTH: usize = 4096;
+
+type ProcessCallback = Box<dyn FnMut(&Client, &ProcessScope) -> Control + Send>;
+type ActiveClient = AsyncClient<ShutdownHandler, ClosureProcessHandler<(), ProcessCallback>>;
+
+/// Records a JACK MIDI input port. Event timestamps are JACK frame times, so they are as
+/// precise as the audio clock. If the JACK server stops, the client is created again once
+/// it is back.
+pub struct JackInput {
+    port_name: String,
+    /// Output ports that are connected to the input automatically.
+    connect: PortSelection,
+    record_timecode: bool,
+    track: Option<usize>,
+    client: Option<(ActiveClient, Port<Unowned>)>,
+    shut_down: Arc<AtomicBool>,
+    /// Timestamp of the last message, held notes are released at it when the server stops.
+    last_timestamp: Arc<AtomicU64>,
+    /// Whether the last attempt to start the client failed, to report failures once.
+    failed: bool,
+}
+
+impl JackInput {
+    /// `port_name` is the name of the input port, `connect` selects output ports of other
+    /// clients to connect to it by name prefix.
+    pub fn new(port_name: String, connect: PortSelection, record_timecode: bool) -> Self {
+        JackInput {
+            port_name,
+            connect,
+            record_timecode,
+            track: None,
+            client: None,
+            shut_down: Arc::new(AtomicBool::new(false)),
+            last_timestamp: Arc::new(AtomicU64::new(0)),
+            failed: false,
+        }
+    }
+
+    fn start(
+        &mut self,
+        session: &SharedSession,
+    ) -> Result<(ActiveClient, Port<Unowned>), Box<dyn Error>> {
+        let track = *self
+            .track
+            .get_or_insert_with(|| session.lock().unwrap().add_track(self.port_name.clone()));
+        let (client, _) = Client::new(PACKAGE_NAME, ClientOptions::NO_START_SERVER)?;
+        let port = client.register_port(&self.port_name, MidiIn::default())?;
+        let unowned_port = port.clone_unowned();
+        let (sender, receiver) = sync_channel::<JackMessage>(QUEUE_LENGTH);
+        let process: ProcessCallback = Box::new(move |client, scope| {
+            let cycle_start = scope.last_frame_time();
+            for message in port.iter(scope) {
+                let timestamp = client.frames_to_time(cycle_start.wrapping_add(message.time));
+                JackMessage::send(&sender, timestamp, message.bytes);
+            }
+            Control::Continue
+        });
+        self.shut_down.store(false, Ordering::Relaxed);
+        let client = client.activate_async(
+            ShutdownHandler(self.shut_down.clone()),
+            ClosureProcessHandler::new(process),
+        )?;
+
+        // Session locking and output stay out of the real-time process callback.
+        let session = session.clone();
+        let name = unowned_port.name()?;
+        println!("Created JACK MIDI input '{}'", name);
+        let record_timecode = self.record_timecode;
+        let last_timestamp = self.last_timestamp.clone();
+        std::thread::spawn(move || {
+            let mut sysex = SysExAssembler::default();
+            // Ends when the client is dropped along with the sender.
+            for message in receiver {
+                last_timestamp.store(message.timestamp, Ordering::Relaxed);
+                record_message(
+                    &session,
+                    track,
+                    &name,
+                    message.bytes(),
+                    message.timestamp,
+                    &mut sysex,
+                    record_timecode,
+                );
+            }
+        });
+        Ok((client, unowned_port))
+    }
+
+    /// Connects output ports that match the selection and are not connected yet.
+    fn connect_outputs(&self) -> Result<(), Box<dyn Error>> {
+        let Some((client, port)) = &self.client else {
+            return Ok(());
+        };
+        let client = client.as_client();
+        let outputs = client.ports(None, Some(&port.port_type()?), PortFlags::IS_OUTPUT);
+        for output in outputs {
+            let selected = match &self.connect {
+                PortSelection::Prefixes(prefixes) => prefixes.iter().any(|p| output.starts_with(p)),
+                PortSelection::All => true,
+            };
+            if selected && !port.is_connected_to(&output)? {
+                client.connect_ports_by_name(&output, &port.name()?)?;
+                println!("Connected JACK MIDI output '{}'", output);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MidiSource for JackInput {
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        if self.client.is_some() && self.shut_down.load(Ordering::Relaxed) {
+            println!("\nJACK server has shut down.");
+            self.client = None;
+            if let Some(track) = self.track {
+                let timestamp = self.last_timestamp.load(Ordering::Relaxed);
+                session.lock().unwrap().release_held(track, timestamp);
+            }
+        }
+        if self.client.is_none() {
+            match self.start(session) {
+                Ok(client) => {
+                    self.client = Some(client);
+                    self.failed = false;
+                }
+                Err(e) if !self.failed => {
+                    self.failed = true;
+                    return Err(format!("Cannot start JACK client: {}", e).into());
+                }
+                Err(_) => return Ok(()),
+            }
+        }
+        self.connect_outputs()
+    }
+
+    fn connected_count(&self) -> usize {
+        self.client.iter().count()
+    }
+
+    fn input_count(&self) -> usize {
+        1
+    }
+}
+
+/// Tells the source that the JACK server has stopped the client.
+struct ShutdownHandler(Arc<AtomicBool>);
+
+impl NotificationHandler for ShutdownHandler {
+    unsafe fn shutdown(&mut self, _status: ClientStatus, _reason: &str) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Message copied out of the process callback, only SysEx needs memory to be allocated.
+struct JackMessage {
+    /// Microseconds of the JACK clock.
+    timestamp: u64,
+    short: [u8; 3],
+    length: usize,
+    long: Vec<u8>,
+}
+
+impl JackMessage {
+    fn send(sender: &SyncSender<JackMessage>, timestamp: u64, bytes: &[u8]) {
+        let mut message = JackMessage {
+            timestamp,
+            short: [0; 3],
+            length: bytes.len(),
+            long: Vec::new(),
+        };
+        if bytes.len() <= message.short.len() {
+            message.short[..bytes.len()].copy_from_slice(bytes);
+        } else {
+            message.long = bytes.to_vec();
+        }
+        // Messages are dropped rather than blocking the audio thread.
+        let _ = sender.try_send(message);
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.long.is_empty() {
+            &self.short[..self.length]
+        } else {
+            &self.long
+        }
+    }
+}
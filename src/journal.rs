@@ -0,0 +1,208 @@
+use crate::tempo::ClockState;
+use chrono::{DateTime, Local};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+
+pub const JOURNAL_EXTENSION: &str = "journal";
+
+/// Write-ahead log of the take that is being recorded, so it can be recovered
+/// if the program dies before the take is saved.
+///
+/// It is a text file with a line per record:
+/// `S <take start time>`, `T <track> <track name>`, `E <track> <timestamp> <hex bytes>`,
+/// `K <track> <timestamp>` where a control marked the take as a keeper and
+/// `C <track> <last pulse> <phase> <beat start> <microseconds per pulse>` with the MIDI clock
+/// that was followed when the take started, `-` stands for an unknown value.
+/// A line cut short by a crash is ignored on recovery.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    pub fn create(directory: &Path, start_time: DateTime<Local>) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(format!(
+            ".{}-{}-{}.{}",
+            env!("CARGO_PKG_NAME"),
+            start_time.format("%Y-%m-%d_%H:%M:%S"),
+            std::process::id(),
+            JOURNAL_EXTENSION
+        ));
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        // Tells recovery that the journal is still in use.
+        file.lock()?;
+        let mut journal = Journal { path, file };
+        journal.write_line(&format!("S {}", start_time.to_rfc3339()))?;
+        Ok(journal)
+    }
+
+    pub fn track(&mut self, track: usize, name: &str) -> io::Result<()> {
+        self.write_line(&format!("T {} {}", track, name))
+    }
+
+    pub fn event(&mut self, track: usize, timestamp: u64, bytes: &[u8]) -> io::Result<()> {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.write_line(&format!("E {} {} {}", track, timestamp, hex))
+    }
+
+    pub fn keep(&mut self, track: usize, timestamp: u64) -> io::Result<()> {
+        self.write_line(&format!("K {} {}", track, timestamp))
+    }
+
+    pub fn clock(&mut self, clock: &ClockState) -> io::Result<()> {
+        let unknown = || "-".to_string();
+        self.write_line(&format!(
+            "C {} {} {} {} {}",
+            clock.port,
+            clock.last_pulse,
+            clock.phase,
+            clock.beat_start.map_or_else(unknown, |t| t.to_string()),
+            clock.usec_per_pulse.map_or_else(unknown, |u| u.to_string())
+        ))
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(format!("{}\n", line).as_bytes())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Deletes the journal once its take is safely stored.
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+/// Input of the take, in the order it was recorded.
+pub enum JournalEvent {
+    /// Raw message bytes.
+    Midi(Vec<u8>),
+    /// The take was marked as a keeper.
+    Keep,
+    /// MIDI clock followed at the start of the take, with the track and last pulse of the record.
+    Clock(ClockState),
+}
+
+/// Contents of a journal left over from an earlier run.
+pub struct JournalContents {
+    pub start_time: Option<DateTime<Local>>,
+    pub track_names: Vec<String>,
+    /// Track index, timestamp and event.
+    pub events: Vec<(usize, u64, JournalEvent)>,
+}
+
+/// Journals in the directory that are not used by a running recorder.
+pub fn find_abandoned(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    if !directory.is_dir() {
+        return Ok(result);
+    }
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == JOURNAL_EXTENSION) {
+            match File::open(&path)?.try_lock_shared() {
+                Ok(()) => result.push(path),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+pub fn read(path: &Path) -> io::Result<JournalContents> {
+    let mut contents = JournalContents {
+        start_time: None,
+        track_names: Vec::new(),
+        events: Vec::new(),
+    };
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut fields = line.splitn(3, ' ');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("S"), Some(time), None) => {
+                contents.start_time = DateTime::parse_from_rfc3339(time)
+                    .ok()
+                    .map(|t| t.with_timezone(&Local));
+            }
+            (Some("T"), Some(track), Some(name)) => {
+                if let Ok(track) = track.parse::<usize>() {
+                    if contents.track_names.len() <= track {
+                        contents.track_names.resize(track + 1, String::new());
+                    }
+                    contents.track_names[track] = name.to_string();
+                }
+            }
+            (Some("E"), Some(track), Some(rest)) => {
+                if let Some(event) = parse_event(track, rest) {
+                    contents.events.push(event);
+                }
+            }
+            (Some("K"), Some(track), Some(timestamp)) => {
+                if let (Ok(track), Ok(timestamp)) = (track.parse(), timestamp.parse()) {
+                    contents.events.push((track, timestamp, JournalEvent::Keep));
+                }
+            }
+            (Some("C"), Some(track), Some(rest)) => {
+                if let Some(event) = parse_clock(track, rest) {
+                    contents.events.push(event);
+                }
+            }
+            _ => {}
+        }
+    }
+    let track_count = contents.events.iter().map(|e| e.0 + 1).max().unwrap_or(0);
+    if contents.track_names.len() < track_count {
+        contents.track_names.resize(track_count, String::new());
+    }
+    Ok(contents)
+}
+
+fn parse_event(track: &str, rest: &str) -> Option<(usize, u64, JournalEvent)> {
+    let (timestamp, hex) = rest.split_once(' ')?;
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((
+        track.parse().ok()?,
+        timestamp.parse().ok()?,
+        JournalEvent::Midi(bytes),
+    ))
+}
+
+fn parse_clock(track: &str, rest: &str) -> Option<(usize, u64, JournalEvent)> {
+    let fields: Vec<_> = rest.split(' ').collect();
+    let [last_pulse, phase, beat_start, usec_per_pulse] = fields[..] else {
+        return None;
+    };
+    let clock = ClockState {
+        port: track.parse().ok()?,
+        last_pulse: last_pulse.parse().ok()?,
+        phase: phase.parse().ok()?,
+        beat_start: parse_unknown(beat_start)?,
+        usec_per_pulse: parse_unknown(usec_per_pulse)?,
+    };
+    Some((clock.port, clock.last_pulse, JournalEvent::Clock(clock)))
+}
+
+/// Value of a field that may be `-`, None if the field is invalid.
+fn parse_unknown<T: FromStr>(field: &str) -> Option<Option<T>> {
+    match field {
+        "-" => Some(None),
+        _ => field.parse().ok().map(Some),
+    }
+}
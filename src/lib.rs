@@ -0,0 +1,60 @@
+//! Continuously records MIDI input and saves each take as a Standard MIDI File.
+//!
+//! A [`Recorder`] takes events from a [`MidiSource`], collects them in a
+//! [`RecordingSession`] and hands each finished take to a [`TakeSink`], such as the
+//! file [`Archive`]. The [`PortWatcher`] source records MIDI input ports, `VirtualPort`
+//! creates a port that other programs connect to, [`RtpMidiListener`] accepts network MIDI
+//! sessions, [`TcpMidiListener`] and [`IpMidiListener`] receive plain MIDI bytes over the
+//! network, [`OscListener`] turns OSC messages into MIDI, and a [`ScriptedSource`] plays
+//! prepared events or a MIDI file, e.g. in tests together with a [`ManualClock`].
+//! [`Controls`] let the musician split, discard or keep takes from the instrument:
+//!
+//! ```no_run
+//! use midi_blackbox::{Archive, PortSelection, PortWatcher, Recorder};
+//!
+//! let source = PortWatcher::new(PortSelection::All, false).unwrap();
+//! let archive = Archive::new("midi-archive".into(), None);
+//! let mut recorder = Recorder::builder(source, archive)
+//!     .journal("midi-archive".into())
+//!     .build();
+//! for _ in 0..60 {
+//!     recorder.poll().unwrap();
+//!     std::thread::sleep(std::time::Duration::from_secs(1));
+//! }
+//! recorder.finish();
+//! ```
+//!
+//! The items re-exported here are the stable API, module internals may change.
+
+mod archive;
+mod clock;
+mod controls;
+#[cfg(feature = "jack")]
+mod jack_input;
+mod journal;
+mod network;
+mod osc;
+mod ports;
+mod recorder;
+mod rtp_midi;
+mod script;
+mod session;
+mod state;
+mod tempo;
+
+pub use archive::{recover_journals, remove_stale_temp_files, Archive};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use controls::{ControlAction, ControlBinding, ControlTrigger, Controls};
+#[cfg(feature = "jack")]
+pub use jack_input::JackInput;
+pub use network::{IpMidiListener, TcpMidiListener, IPMIDI_GROUP, IPMIDI_PORT};
+pub use osc::{OscListener, OscMapping};
+#[cfg(unix)]
+pub use ports::VirtualPort;
+pub use ports::{input_port_names, PortSelection, PortWatcher};
+pub use recorder::{MidiSource, Recorder, RecorderBuilder, SharedSession, Status, TakeSink};
+pub use rtp_midi::{RtpMidiListener, DEFAULT_RTP_MIDI_PORT};
+pub use script::{ScriptedEvent, ScriptedSource};
+pub use session::{RecordingSession, SplitPolicy, SysExAssembler};
+
+const PACKAGE_NAME: &str = env!("CARGO_PKG_NAME");
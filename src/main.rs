@@ -1,241 +1,629 @@
-use chrono::{DateTime, Datelike, Local};
-use clap::{Arg, Command};
-use midir::{MidiInput, MidiInputPort};
-use midly::live::LiveEvent;
-use midly::num::u28;
-use midly::{Format, Header, Smf, Timing, Track, TrackEvent, TrackEventKind};
+mod config;
+mod systemd;
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
+use midi_blackbox::{
+    recover_journals, remove_stale_temp_files, Archive, ControlBinding, Controls, IpMidiListener,
+    MidiSource, OscListener, OscMapping, PortSelection, PortWatcher, Recorder, RtpMidiListener,
+    SplitPolicy, TcpMidiListener, IPMIDI_GROUP,
+};
 use signal_hook::consts::signal::*;
 use signal_hook::flag;
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::{ErrorKind, Write};
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use std::{fs, io};
+use std::sync::Arc;
+use std::time::Duration;
 
 const PACKAGE_NAME: &str = env!("CARGO_PKG_NAME");
-const DEFAULT_USEC_PER_TICK: u32 = 500; // 120 BPM with 1000 ticks per beat
-const DEFAULT_TICKS_PER_BEAT: u16 = 1000;
-
-struct RecordingSession {
-    first_event_time: Option<Instant>,
-    last_event_time: Option<Instant>,
-    usec_per_tick: u32,
-    events: Vec<TrackEvent<'static>>,
-}
-
-impl RecordingSession {
-    fn new() -> Self {
-        RecordingSession {
-            first_event_time: None,
-            last_event_time: None,
-            usec_per_tick: DEFAULT_USEC_PER_TICK,
-            events: Vec::new(),
-        }
-    }
-
-    fn add_event(&mut self, event: LiveEvent<'static>) {
-        let now = Instant::now();
-        if self.first_event_time.is_none() {
-            self.first_event_time = Some(now);
-        }
-        let elapsed_since_last = self
-            .last_event_time
-            .map(|t| now.duration_since(t))
-            .unwrap_or(Duration::ZERO);
-        let delta_ticks =
-            (elapsed_since_last.as_micros() as u64 / self.usec_per_tick as u64) as u32;
-        self.last_event_time = Some(now);
-
-        // Convert LiveEvent to TrackEventKind
-        if let Some(kind) = Self::live_event_to_track_event_kind(event) {
-            self.events.push(TrackEvent {
-                delta: u28::from(delta_ticks),
-                kind,
-            });
-        }
-    }
-
-    fn live_event_to_track_event_kind(
-        event: LiveEvent<'static>,
-    ) -> Option<TrackEventKind<'static>> {
-        match event {
-            LiveEvent::Midi { channel, message } => Some(TrackEventKind::Midi { channel, message }),
-            LiveEvent::Common(_) => None, // Skip common events for now
-            LiveEvent::Realtime(_) => None, // Skip realtime events
-        }
-    }
-
-    fn target_directory(base_path: &PathBuf, time: DateTime<Local>) -> std::io::Result<PathBuf> {
-        let directory = Path::new(base_path)
-            .join(time.year().to_string())
-            .join(time.month().to_string())
-            .join(time.day().to_string());
-
-        fs::create_dir_all(&directory)?;
-
-        if !directory.is_dir() {
-            return Err(io::Error::new(
-                ErrorKind::AlreadyExists,
-                format!("Path exists but is not a directory {}", directory.display()),
-            ));
-        }
-        Ok(directory)
-    }
-
-    fn save_to_file(&mut self, directory: &PathBuf) -> std::io::Result<()> {
-        if self.first_event_time.is_none() {
-            assert!(self.events.is_empty());
-            println!("\nNo events, skipping save.");
-            return Ok(());
-        }
-        assert!(!self.events.is_empty() && self.last_event_time.is_some());
-        let file_time = chrono::Local::now();
-        let file_path = Self::target_directory(directory, file_time)?.join(format!(
-            "{}-{}e-{}s.mid",
-            file_time.format("%Y-%m-%d_%H:%M:%S"),
-            self.events.len() + 1, // + EndOfTrack
-            self.last_event_time
-                .unwrap()
-                .duration_since(self.first_event_time.unwrap())
-                .as_secs_f64()
-                .ceil() as i64
-        ));
-
-        self.events.push(TrackEvent {
-            delta: u28::from(0),
-            kind: TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
-        });
-
-        let timing = Timing::Metrical(midly::num::u15::from(DEFAULT_TICKS_PER_BEAT));
-        let header = Header::new(Format::SingleTrack, timing);
-        let mut smf = Smf::new(header);
-
-        let mut track = Track::new();
-        track.extend_from_slice(&self.events);
-        smf.tracks.push(track);
-
-        let mut output = Vec::new();
-        smf.write(&mut output).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("MIDI write error: {:?}", e),
-            )
-        })?;
-
-        println!("\nWriting recording to {:}", &file_path.display());
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create_new(true) // Do not overwrite.
-            .open(&file_path)?;
-        file.write_all(&output)?;
-        println!("Wrote {} events.", self.events.len());
-        self.reset();
-
-        Ok(())
-    }
-
-    fn reset(&mut self) {
-        self.first_event_time = None;
-        self.last_event_time = None;
-        self.events.clear();
-    }
-}
 
 fn list_midi_inputs() -> Result<(), Box<dyn std::error::Error>> {
-    let midi_input = MidiInput::new(PACKAGE_NAME)?;
-    let ports = midi_input.ports();
+    let names = midi_blackbox::input_port_names()?;
 
-    if ports.is_empty() {
+    if names.is_empty() {
         println!("No MIDI input ports available.");
     } else {
         println!("Available MIDI input ports:\n");
-        for port in ports {
-            let name = midi_input.port_name(&port)?;
+        for name in names {
             println!("\t{}", name);
         }
     }
     Ok(())
 }
 
-fn do_recording(
-    port_name_prefix: &str,
-    output_path: PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let midi_input = MidiInput::new(PACKAGE_NAME)?;
+/// Where MIDI events come from.
+enum Input {
+    Ports(PortSelection),
+    /// Virtual port with the given name.
+    Virtual(String),
+    /// JACK MIDI port, output ports of other clients that match are connected to it.
+    #[cfg_attr(not(feature = "jack"), allow(dead_code))]
+    Jack(PortSelection),
+    /// RTP-MIDI sessions accepted on the UDP control port and the one after it.
+    RtpMidi(u16),
+    /// Plain MIDI bytes over TCP connections to the port.
+    TcpMidi(u16),
+    /// ipMIDI datagrams to the multicast group on the UDP port.
+    IpMidi(u16),
+    /// OSC server on the UDP port, with mappings of addresses to MIDI messages.
+    Osc(u16, Vec<OscMapping>),
+}
 
-    let selected_port = select_port(port_name_prefix, &midi_input)?;
-    let port = selected_port
-        .ok_or_else(|| format!("No MIDI input port found matching '{}'", port_name_prefix))?;
+impl Input {
+    fn source(self, record_timecode: bool) -> Result<Box<dyn MidiSource>, Box<dyn Error>> {
+        Ok(match self {
+            Input::Ports(selection) => Box::new(PortWatcher::new(selection, record_timecode)?),
+            #[cfg(unix)]
+            Input::Virtual(name) => {
+                Box::new(midi_blackbox::VirtualPort::new(name, record_timecode))
+            }
+            #[cfg(not(unix))]
+            Input::Virtual(_) => {
+                return Err("Virtual ports are not supported on this system.".into())
+            }
+            #[cfg(feature = "jack")]
+            Input::Jack(connect) => Box::new(midi_blackbox::JackInput::new(
+                "midi_in".to_string(),
+                connect,
+                record_timecode,
+            )),
+            #[cfg(not(feature = "jack"))]
+            Input::Jack(_) => {
+                return Err("JACK support is not included, build with `--features jack`.".into())
+            }
+            Input::RtpMidi(port) => Box::new(
+                RtpMidiListener::new(PACKAGE_NAME.to_string(), port, record_timecode)
+                    .map_err(|e| format!("Cannot listen on RTP-MIDI port {}: {}", port, e))?,
+            ),
+            Input::TcpMidi(port) => Box::new(
+                TcpMidiListener::new(port, record_timecode)
+                    .map_err(|e| format!("Cannot listen on TCP port {}: {}", port, e))?,
+            ),
+            Input::IpMidi(port) => Box::new(
+                IpMidiListener::new(IPMIDI_GROUP, port, record_timecode)
+                    .map_err(|e| format!("Cannot listen for ipMIDI on port {}: {}", port, e))?,
+            ),
+            Input::Osc(port, mappings) => Box::new(
+                OscListener::new(port, mappings, record_timecode)
+                    .map_err(|e| format!("Cannot listen for OSC on port {}: {}", port, e))?,
+            ),
+        })
+    }
+}
 
-    let session = Arc::new(Mutex::new(RecordingSession::new()));
-    let session_clone = session.clone();
+/// Recording settings resolved from the command line and the configuration file.
+struct RecordingOptions {
+    input: Input,
+    archive_dir: PathBuf,
+    fallback_dir: Option<PathBuf>,
+    record_timecode: bool,
+    policy: SplitPolicy,
+    controls: Vec<ControlBinding>,
+    filter_controls: bool,
+    wait_for_port: bool,
+}
 
-    println!("Recording...");
-    println!("Press Ctrl+C to stop.\n");
-
-    let _connection = midi_input.connect(
-        &port,
-        PACKAGE_NAME,
-        move |timestamp, message, _| {
-            // Skip active sensing and clock messages
-            if message[0] == 0xFE || message[0] == 0xF8 {
-                return;
+impl RecordingOptions {
+    fn resolve(options: config::Options) -> Result<Self, Box<dyn Error>> {
+        let input = if let Some(name) = options.virtual_port {
+            Input::Virtual(name)
+        } else if options.jack == Some(true) {
+            Input::Jack(if options.all_ports == Some(true) {
+                PortSelection::All
+            } else {
+                PortSelection::Prefixes(options.ports.unwrap_or_default())
+            })
+        } else if let Some(port) = options.rtp_midi_port {
+            Input::RtpMidi(port)
+        } else if let Some(port) = options.tcp_midi_port {
+            Input::TcpMidi(port)
+        } else if let Some(port) = options.ipmidi_port {
+            Input::IpMidi(port)
+        } else if let Some(port) = options.osc_port {
+            let mut mappings = Vec::new();
+            for mapping in options.osc_mappings.unwrap_or_default() {
+                mappings.push(mapping.parse()?);
+            }
+            Input::Osc(port, mappings)
+        } else if options.all_ports == Some(true) {
+            Input::Ports(PortSelection::All)
+        } else {
+            match options.ports {
+                Some(ports) if !ports.is_empty() => Input::Ports(PortSelection::Prefixes(ports)),
+                _ => return Err(
+                    "No MIDI input given, use --port, --all-ports, --virtual or a network input."
+                        .into(),
+                ),
             }
+        };
+        let mut controls = Vec::new();
+        for control in options.controls.unwrap_or_default() {
+            controls.push(control.parse()?);
+        }
+        let defaults = SplitPolicy::default();
+        Ok(RecordingOptions {
+            input,
+            archive_dir: options
+                .archive_dir
+                .ok_or("No archive directory given, use --archive-dir.")?,
+            fallback_dir: options.fallback_dir,
+            record_timecode: options.record_timecode.unwrap_or(false),
+            policy: SplitPolicy {
+                pause: options
+                    .split_after
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.pause),
+                max_length: options.max_take_length.map(Duration::from_secs),
+                wait_for_release: !options.split_while_held.unwrap_or(false),
+                min_events: options.min_events.unwrap_or(defaults.min_events),
+                follow_transport: options.follow_transport.unwrap_or(false),
+            },
+            controls,
+            filter_controls: options.filter_controls.unwrap_or(false),
+            wait_for_port: options.wait_for_port.unwrap_or(false),
+        })
+    }
+}
 
-            if let Ok(live_event) = LiveEvent::parse(message) {
-                let static_event = live_event.to_static();
-                println!("@ {}: {:?}", timestamp, static_event);
+/// Records until stopped, `load_options` is used to reload the split policy on SIGHUP.
+fn do_recording(
+    options: RecordingOptions,
+    load_options: impl Fn() -> Result<RecordingOptions, Box<dyn Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let RecordingOptions {
+        input,
+        archive_dir: output_path,
+        fallback_dir: fallback_path,
+        record_timecode,
+        policy,
+        controls,
+        filter_controls,
+        wait_for_port,
+    } = options;
+    if let Err(e) = remove_stale_temp_files(&output_path) {
+        eprintln!(
+            "Warning: cannot clean up archive {}: {}",
+            output_path.display(),
+            e
+        );
+    }
+    if let Err(e) = recover_journals(&output_path) {
+        eprintln!("Warning: cannot recover takes from journals: {}", e);
+    }
+    let mut builder = Recorder::builder(
+        input.source(record_timecode)?,
+        Archive::new(output_path.clone(), fallback_path),
+    )
+    .policy(policy)
+    .journal(output_path);
+    if !controls.is_empty() {
+        builder = builder.controls(Controls::new(controls, filter_controls));
+    }
+    let mut recorder = builder.build();
+    let mut notifier = systemd::Notifier::from_env();
+    if let Err(e) = recorder.poll() {
+        if !wait_for_port {
+            return Err(e);
+        }
+        println!("{}, waiting for the input to become available.", e);
+    }
+    if let Some(missing) = recorder.source().missing_input() {
+        let message = format!("No MIDI input port found matching '{}'", missing);
+        if !wait_for_port {
+            return Err(message.into());
+        }
+        println!("{}, waiting for it to appear.", message);
+    }
 
-                let mut session = session_clone.lock().unwrap();
-                session.add_event(static_event);
-            }
-        },
-        (),
-    )?;
+    println!("Recording...");
+    println!("Press Ctrl+C to stop, send SIGUSR1 to save the current take now.\n");
 
     let stop = Arc::new(AtomicBool::new(false));
-    flag::register(SIGINT, Arc::clone(&stop))?;
+    for signal in [SIGINT, SIGTERM, SIGQUIT] {
+        // A second signal terminates the program without waiting for the take to be saved.
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))?;
+        flag::register(signal, Arc::clone(&stop))?;
+    }
+    let reload = Arc::new(AtomicBool::new(false));
+    flag::register(SIGHUP, Arc::clone(&reload))?;
+    let split_now = Arc::new(AtomicBool::new(false));
+    flag::register(SIGUSR1, Arc::clone(&split_now))?;
 
+    let mut ready = false;
     while !stop.load(Ordering::Relaxed) {
+        let status = recorder.status();
+        if !ready && status.connected_inputs > 0 {
+            notifier.ready();
+            ready = true;
+        }
+        notifier.watchdog();
+        notifier.status(&format!(
+            "Connected {} of {} inputs, {}.",
+            status.connected_inputs,
+            status.inputs,
+            match status.take_events {
+                0 => "no take in progress".to_string(),
+                n => format!("current take has {} events", n),
+            }
+        ));
+
         std::thread::sleep(Duration::from_secs(1));
-        if let Ok(mut session) = session.try_lock() {
-            if let Some(t) = session.last_event_time {
-                if Instant::now().duration_since(t) > Duration::from_secs(8) {
-                    session.save_to_file(&output_path)?;
+        if reload.swap(false, Ordering::Relaxed) {
+            // Output goes to stdout and stderr, so there are no log files to reopen here.
+            println!("\nReloading: retrying pending saves.");
+            recorder.retry_now();
+            match load_options() {
+                Ok(options) => {
+                    recorder.set_policy(options.policy);
+                    println!(
+                        "Reloaded split_after, max_take_length, min_events, split_while_held and \
+                         follow_transport. Inputs, directories and controls change on restart."
+                    );
                 }
+                Err(e) => eprintln!("Cannot reload configuration: {}", e),
             }
         }
+        if split_now.swap(false, Ordering::Relaxed) {
+            println!("\nSplit requested.");
+            recorder.split();
+        }
+        if let Err(e) = recorder.poll() {
+            eprintln!("Cannot update MIDI input ports: {}", e);
+        }
     }
+    notifier.stopping();
 
-    session.lock().unwrap().save_to_file(&output_path)?;
+    if !recorder.finish() {
+        return Err("Could not save the last take.".into());
+    }
 
     println!("Bye.");
     Ok(())
 }
 
-fn select_port(
-    port_name_prefix: &str,
-    midi_input: &MidiInput,
-) -> Result<Option<MidiInputPort>, Box<dyn Error>> {
-    let ports = midi_input.ports();
-    for port in &ports {
-        let name = midi_input.port_name(port)?;
-        if name.starts_with(port_name_prefix.trim()) {
-            println!("Selected MIDI input: '{}'", name);
-            return Ok(Some(port.clone()));
+/// Options given on the command line.
+fn command_line_options(matches: &ArgMatches) -> config::Options {
+    let flag = |id| matches.get_flag(id).then_some(true);
+    let ports: Option<Vec<String>> = matches
+        .get_many::<String>("port")
+        .map(|ports| ports.cloned().collect());
+    config::Options {
+        archive_dir: matches.get_one::<PathBuf>("archive directory").cloned(),
+        fallback_dir: matches.get_one::<PathBuf>("fallback directory").cloned(),
+        all_ports: flag("all ports"),
+        ports,
+        virtual_port: matches.get_one::<String>("virtual port").cloned(),
+        jack: flag("jack"),
+        rtp_midi_port: matches.get_one::<u16>("rtp midi port").copied(),
+        tcp_midi_port: matches.get_one::<u16>("tcp midi port").copied(),
+        ipmidi_port: matches.get_one::<u16>("ipmidi port").copied(),
+        osc_port: matches.get_one::<u16>("osc port").copied(),
+        osc_mappings: matches
+            .get_many::<String>("osc mapping")
+            .map(|mappings| mappings.cloned().collect()),
+        wait_for_port: flag("wait for port"),
+        record_timecode: flag("record timecode"),
+        split_after: matches.get_one::<u64>("split after").copied(),
+        max_take_length: matches.get_one::<u64>("max take length").copied(),
+        split_while_held: flag("split while held"),
+        follow_transport: flag("follow transport"),
+        controls: matches
+            .get_many::<String>("control")
+            .map(|controls| controls.cloned().collect()),
+        filter_controls: flag("filter controls"),
+        min_events: matches.get_one::<usize>("min events").copied(),
+    }
+}
+
+fn recording_options(matches: &ArgMatches) -> Result<RecordingOptions, Box<dyn Error>> {
+    let config = config::Config::load(matches.get_one::<PathBuf>("config").map(|p| p.as_path()))?;
+    let profile = matches.get_one::<String>("profile").map(|p| p.as_str());
+    let options = merge_options(command_line_options(matches), config.options(profile)?);
+    RecordingOptions::resolve(options)
+}
+
+/// Options of the command line on top of the configured ones.
+fn merge_options(command_line: config::Options, configured: config::Options) -> config::Options {
+    let input_given = command_line.all_ports.is_some()
+        || command_line.ports.is_some()
+        || command_line.virtual_port.is_some()
+        || command_line.jack.is_some()
+        || command_line.rtp_midi_port.is_some()
+        || command_line.tcp_midi_port.is_some()
+        || command_line.ipmidi_port.is_some()
+        || command_line.osc_port.is_some();
+    let mut options = command_line.clone().or(configured);
+    if input_given {
+        // An input given on the command line replaces the one of the configuration.
+        options.all_ports = command_line.all_ports;
+        options.virtual_port = command_line.virtual_port;
+        options.jack = command_line.jack;
+        options.rtp_midi_port = command_line.rtp_midi_port;
+        options.tcp_midi_port = command_line.tcp_midi_port;
+        options.ipmidi_port = command_line.ipmidi_port;
+        options.osc_port = command_line.osc_port;
+    }
+    options
+}
+
+/// Recording options that are paths, the service may run in another directory.
+const PATH_ARGS: [&str; 3] = ["config", "archive directory", "fallback directory"];
+
+/// Command line that runs the recorder with every recording option given.
+fn service_command(matches: &ArgMatches) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut command = vec![
+        std::env::current_exe()?.display().to_string(),
+        "record".to_string(),
+    ];
+    // A system service does not see the configuration of the installing user.
+    if matches.get_one::<PathBuf>("config").is_none() {
+        if let Some(path) = config::default_path().filter(|p| p.is_file()) {
+            command.push("--config".to_string());
+            command.push(std::path::absolute(path)?.display().to_string());
         }
     }
-    Ok(None)
+    for arg in recording_args() {
+        let id = arg.get_id().as_str();
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let long = arg.get_long().ok_or("Recording options have long names")?;
+        if !arg.get_action().takes_values() {
+            command.push(format!("--{}", long));
+        } else if PATH_ARGS.contains(&id) {
+            for path in matches.get_many::<PathBuf>(id).into_iter().flatten() {
+                let path = std::path::absolute(path)?;
+                command.push(format!("--{}={}", long, path.display()));
+            }
+        } else {
+            for value in matches.get_raw(id).into_iter().flatten() {
+                command.push(format!("--{}={}", long, value.to_string_lossy()));
+            }
+        }
+    }
+    if !matches.get_flag("wait for port") {
+        command.push("--wait-for-port".to_string());
+    }
+    Ok(command)
 }
 
-fn main() {
-    let matches = Command::new(PACKAGE_NAME)
+/// Writes a systemd unit that runs the recorder with the given options.
+fn install_service(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    // Fail early rather than have the service fail to start.
+    recording_options(matches)?;
+
+    let command = service_command(matches)?;
+
+    let system = matches.get_flag("system");
+    let unit = systemd::unit_file(
+        &command,
+        system,
+        matches
+            .get_one::<String>("service user")
+            .map(|s| s.as_str()),
+    );
+    if matches.get_flag("print") {
+        print!("{}", unit);
+        return Ok(());
+    }
+
+    let unit_directory = if system {
+        PathBuf::from("/etc/systemd/system")
+    } else {
+        config::config_home()
+            .ok_or("Cannot find user configuration directory")?
+            .join("systemd/user")
+    };
+    fs::create_dir_all(&unit_directory)?;
+    let unit_path = unit_directory.join(format!("{}.service", PACKAGE_NAME));
+    fs::write(&unit_path, unit)?;
+    println!("Wrote {}", unit_path.display());
+    let systemctl = if system {
+        "systemctl"
+    } else {
+        "systemctl --user"
+    };
+    println!("To start the service now and on boot, run:");
+    println!("\t{} daemon-reload", systemctl);
+    println!("\t{} enable --now {}", systemctl, PACKAGE_NAME);
+    if !system {
+        println!("To keep it running while you are logged out: loginctl enable-linger");
+    }
+    Ok(())
+}
+
+/// Options of recording, shared by the `record` command and the top level command line.
+fn recording_args() -> Vec<Arg> {
+    vec![
+        Arg::new("config")
+            .short('c')
+            .long("config")
+            .value_name("FILE")
+            .help("Configuration file, by default $XDG_CONFIG_HOME/midi-blackbox/config.toml")
+            .value_parser(clap::value_parser!(PathBuf)),
+        Arg::new("profile")
+            .long("profile")
+            .value_name("NAME")
+            .help("Use options of this profile from the configuration file."),
+        Arg::new("port")
+            .short('p')
+            .long("port")
+            .value_name("PORT_PREFIX")
+            .help("MIDI input port name prefix to use. Can be repeated to record several ports.")
+            .action(clap::ArgAction::Append),
+        Arg::new("all ports")
+            .long("all-ports")
+            .help("Record from all available MIDI input ports.")
+            .conflicts_with("port")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("virtual port")
+            .long("virtual")
+            .value_name("NAME")
+            .help("Create a MIDI input port with this name that other programs can connect to.")
+            .conflicts_with_all(["port", "all ports"]),
+        Arg::new("jack")
+            .long("jack")
+            .help(
+                "Record from a JACK MIDI input port. Output ports given with --port or \
+                 --all-ports are connected to it.",
+            )
+            .conflicts_with("virtual port")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("rtp midi port")
+            .long("rtp-midi")
+            .value_name("PORT")
+            .help(
+                "Accept RTP-MIDI (AppleMIDI) network sessions on this UDP port and the next one. \
+                 [default: 5004]",
+            )
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("5004")
+            .value_parser(clap::value_parser!(u16))
+            .conflicts_with_all(["port", "all ports", "virtual port", "jack"]),
+        Arg::new("tcp midi port")
+            .long("tcp-midi")
+            .value_name("PORT")
+            .help("Accept TCP connections that send plain MIDI bytes on this port.")
+            .value_parser(clap::value_parser!(u16))
+            .conflicts_with_all(["port", "all ports", "virtual port", "jack", "rtp midi port"]),
+        Arg::new("ipmidi port")
+            .long("ipmidi")
+            .value_name("PORT")
+            .help("Receive ipMIDI multicast on this UDP port. [default: 21928]")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("21928")
+            .value_parser(clap::value_parser!(u16))
+            .conflicts_with_all([
+                "port",
+                "all ports",
+                "virtual port",
+                "jack",
+                "rtp midi port",
+                "tcp midi port",
+            ]),
+        Arg::new("osc port")
+            .long("osc")
+            .value_name("PORT")
+            .help(
+                "Receive OSC messages on this UDP port. MIDI messages with the 'm' type tag are \
+                 recorded, see --osc-map for other messages.",
+            )
+            .value_parser(clap::value_parser!(u16))
+            .conflicts_with_all([
+                "port",
+                "all ports",
+                "virtual port",
+                "jack",
+                "rtp midi port",
+                "tcp midi port",
+                "ipmidi port",
+            ]),
+        Arg::new("osc mapping")
+            .long("osc-map")
+            .value_name("MAPPING")
+            .help(
+                "Record OSC messages to an address as MIDI, e.g. \"/note channel pitch velocity\" \
+                 or \"/1/fader1 controller=7 value\". Can be repeated.",
+            )
+            .action(clap::ArgAction::Append),
+        Arg::new("wait for port")
+            .long("wait-for-port")
+            .help("Wait for MIDI input ports to appear instead of exiting if they are absent.")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("archive directory")
+            .short('o')
+            .long("archive-dir")
+            .value_name("FILE")
+            .help(
+                "Root directory where recorded MIDI files should be stored.\
+                      Will be created if it does not exist.",
+            )
+            .value_parser(clap::value_parser!(PathBuf)),
+        Arg::new("fallback directory")
+            .long("fallback-dir")
+            .value_name("FILE")
+            .help("Directory to save recordings to when the archive directory is not writable.")
+            .value_parser(clap::value_parser!(PathBuf)),
+        Arg::new("record timecode")
+            .long("record-timecode")
+            .help("Also record MIDI Time Code quarter frame messages.")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("split after")
+            .long("split-after")
+            .value_name("SECONDS")
+            .help("Pause that ends a take. [default: 8]")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("max take length")
+            .long("max-take-length")
+            .value_name("SECONDS")
+            .help("Split takes that are longer than this even if playing continues.")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("split while held")
+            .long("split-while-held")
+            .help("End a take on pause even if notes or pedals are still held.")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("follow transport")
+            .long("follow-transport")
+            .help("Start a take on transport Start or Continue and end it on Stop.")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("control")
+            .long("control")
+            .value_name("ACTION=TRIGGER")
+            .help(
+                "Let MIDI input split, discard or keep takes, e.g. 'split=cc80', \
+                 'keep=cc81' or 'discard=note21+22+23' for keys held together.",
+            )
+            .action(clap::ArgAction::Append),
+        Arg::new("filter controls")
+            .long("filter-controls")
+            .help("Leave controllers and keys bound with --control out of the recording.")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("min events")
+            .long("min-events")
+            .value_name("COUNT")
+            .help("Discard takes that have fewer events than this. [default: 1]")
+            .value_parser(clap::value_parser!(usize)),
+    ]
+}
+
+fn command() -> Command {
+    Command::new(PACKAGE_NAME)
         .version(env!("CARGO_PKG_VERSION"))
         .author("Petr Gladkikh")
         .about("Continuously records MIDI events from given MIDI sequencer to file archive.")
+        .subcommand(
+            Command::new("record")
+                .about("Record MIDI input to the archive (the same as without a command).")
+                .args(recording_args()),
+        )
+        .subcommand(
+            Command::new("install-service")
+                .about("Write a systemd unit that runs the recorder in background.")
+                .args(recording_args())
+                .arg(
+                    Arg::new("system")
+                        .long("system")
+                        .help("Install a system service instead of a user one.")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("service user")
+                        .long("service-user")
+                        .value_name("USER")
+                        .help("User to run the system service as.")
+                        .requires("system"),
+                )
+                .arg(
+                    Arg::new("print")
+                        .long("print")
+                        .help("Print the unit instead of installing it.")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
         .arg(
             Arg::new("list")
                 .short('l')
@@ -243,38 +631,17 @@ fn main() {
                 .help("List available MIDI input ports.")
                 .action(clap::ArgAction::SetTrue),
         )
-        .arg(
-            Arg::new("port")
-                .short('p')
-                .long("port")
-                .value_name("PORT_PREFIX")
-                .help("MIDI input port name prefix to use.")
-                .required_unless_present("list"),
-        )
-        .arg(
-            Arg::new("archive directory")
-                .short('o')
-                .long("archive-dir")
-                .value_name("FILE")
-                .help(
-                    "Root directory where recorded MIDI files should be stored.\
-                          Will be created if it does not exist.",
-                )
-                .value_parser(clap::value_parser!(PathBuf))
-                .required_unless_present("list"),
-        )
-        .get_matches();
+        .args(recording_args())
+}
 
-    let result = if matches.get_flag("list") {
-        list_midi_inputs()
-    } else {
-        let port_prefix = matches.get_one::<String>("port").unwrap();
-        let output_path = matches
-            .get_one::<PathBuf>("archive directory")
-            .unwrap()
-            .clone();
+fn main() {
+    let matches = command().get_matches();
 
-        do_recording(port_prefix, output_path)
+    let result = match matches.subcommand() {
+        Some(("install-service", matches)) => install_service(matches),
+        Some(("record", matches)) => record(matches),
+        _ if matches.get_flag("list") => list_midi_inputs(),
+        _ => record(&matches),
     };
 
     if let Err(e) = result {
@@ -282,3 +649,284 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+fn record(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    do_recording(recording_options(matches)?, || recording_options(matches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_gets_every_recording_option() {
+        let inputs = [
+            "--port=MPK",
+            "--all-ports",
+            "--virtual=Recorder",
+            "--jack",
+            "--rtp-midi",
+            "--tcp-midi=5008",
+            "--ipmidi",
+            "--osc=8000",
+        ];
+        let mut exec_start = String::new();
+        for input in inputs {
+            let matches = command()
+                .try_get_matches_from([
+                    PACKAGE_NAME,
+                    "install-service",
+                    input,
+                    "--config=/etc/midi-blackbox.toml",
+                    "--profile=drums",
+                    "--osc-map=/note channel pitch velocity",
+                    "--archive-dir=/archive",
+                    "--fallback-dir=/fallback",
+                    "--record-timecode",
+                    "--split-after=3",
+                    "--max-take-length=600",
+                    "--split-while-held",
+                    "--follow-transport",
+                    "--control=split=cc80",
+                    "--filter-controls",
+                    "--min-events=20",
+                ])
+                .unwrap();
+            let (_, matches) = matches.subcommand().unwrap();
+            let unit = systemd::unit_file(&service_command(matches).unwrap(), false, None);
+            let line = unit.lines().find(|l| l.starts_with("ExecStart=")).unwrap();
+            assert!(line.contains(&format!("\"{}", input)), "{}", line);
+            exec_start.push_str(line);
+        }
+        assert!(exec_start.contains("\"--osc-map=/note channel pitch velocity\""));
+        assert!(exec_start.contains("\"--rtp-midi=5004\""));
+        for arg in recording_args() {
+            let long = format!("\"--{}", arg.get_long().unwrap());
+            assert!(exec_start.contains(&long), "{} is not forwarded", long);
+        }
+    }
+
+    /// Base options with a profile for a drum kit.
+    fn config() -> config::Config {
+        config::Config::parse(
+            r#"
+            archive_dir = "/archive"
+            split_after = 10
+
+            [profiles.drums]
+            ports = ["TD-17"]
+            split_after = 3
+            min_events = 20
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn options_are_merged() {
+        let config = config();
+        let command_line = config::Options {
+            min_events: Some(5),
+            ..config::Options::default()
+        };
+        let options =
+            RecordingOptions::resolve(command_line.or(config.options(Some("drums")).unwrap()))
+                .unwrap();
+        assert_eq!(options.archive_dir, PathBuf::from("/archive"));
+        assert!(
+            matches!(options.input, Input::Ports(PortSelection::Prefixes(ref p)) if p == &["TD-17"])
+        );
+        assert_eq!(options.policy.pause, Duration::from_secs(3));
+        assert_eq!(options.policy.min_events, 5);
+        assert!(config.options(Some("piano")).is_err());
+        assert!(RecordingOptions::resolve(config.options(None).unwrap()).is_err());
+        assert!(config::Config::parse("split_afterr = 1").is_err());
+    }
+
+    /// Options on top of the base configuration and a check of the input they choose.
+    type InputCase = (config::Options, fn(&Input) -> bool);
+
+    #[test]
+    fn input_is_chosen() {
+        let cases: Vec<InputCase> = vec![
+            (
+                config::Options {
+                    virtual_port: Some("Blackbox".to_string()),
+                    ports: Some(vec!["TD-17".to_string()]),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::Virtual(name) if name == "Blackbox"),
+            ),
+            (
+                config::Options {
+                    jack: Some(true),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::Jack(PortSelection::Prefixes(p)) if p.is_empty()),
+            ),
+            (
+                config::Options {
+                    jack: Some(true),
+                    all_ports: Some(true),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::Jack(PortSelection::All)),
+            ),
+            (
+                config::Options {
+                    rtp_midi_port: Some(5004),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::RtpMidi(5004)),
+            ),
+            (
+                config::Options {
+                    tcp_midi_port: Some(5008),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::TcpMidi(5008)),
+            ),
+            (
+                config::Options {
+                    ipmidi_port: Some(21928),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::IpMidi(21928)),
+            ),
+            (
+                config::Options {
+                    osc_port: Some(8000),
+                    osc_mappings: Some(vec!["/note pitch velocity".to_string()]),
+                    ..config::Options::default()
+                },
+                |input| matches!(input, Input::Osc(8000, mappings) if mappings.len() == 1),
+            ),
+        ];
+        for (i, (options, expected)) in cases.into_iter().enumerate() {
+            let options = RecordingOptions::resolve(options.or(config().options(None).unwrap()));
+            assert!(expected(&options.unwrap().input), "case {}", i);
+        }
+    }
+
+    #[test]
+    fn command_line_input_replaces_jack() {
+        let config = config::Config::parse(
+            r#"
+            archive_dir = "/archive"
+            jack = true
+            "#,
+        )
+        .unwrap();
+        let command_line = config::Options {
+            rtp_midi_port: Some(5004),
+            ..config::Options::default()
+        };
+        let options = merge_options(command_line, config.options(None).unwrap());
+        assert!(matches!(
+            RecordingOptions::resolve(options).unwrap().input,
+            Input::RtpMidi(5004)
+        ));
+
+        let config = config::Config::parse(
+            r#"
+            archive_dir = "/archive"
+            virtual_port = "Blackbox"
+            "#,
+        )
+        .unwrap();
+        let command_line = config::Options {
+            jack: Some(true),
+            ..config::Options::default()
+        };
+        let options = merge_options(command_line, config.options(None).unwrap());
+        assert!(matches!(
+            RecordingOptions::resolve(options).unwrap().input,
+            Input::Jack(_)
+        ));
+    }
+
+    #[test]
+    fn command_line_ports_replace_configured_input() {
+        for input in [
+            "all_ports = true",
+            "virtual_port = \"Blackbox\"",
+            "osc_port = 8000",
+        ] {
+            let config =
+                config::Config::parse(&format!("archive_dir = \"/archive\"\n{}", input)).unwrap();
+            let command_line = config::Options {
+                ports: Some(vec!["TD-17".to_string()]),
+                ..config::Options::default()
+            };
+            let options = merge_options(command_line, config.options(None).unwrap());
+            assert!(
+                matches!(
+                    RecordingOptions::resolve(options).unwrap().input,
+                    Input::Ports(PortSelection::Prefixes(ref p)) if p == &["TD-17"]
+                ),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn virtual_port_replaces_ports() {
+        assert!(command()
+            .try_get_matches_from([PACKAGE_NAME, "--virtual=Blackbox", "--port=TD-17"])
+            .is_err());
+        let matches = command()
+            .try_get_matches_from([PACKAGE_NAME, "--virtual=Blackbox"])
+            .unwrap();
+        let options = command_line_options(&matches);
+        assert_eq!(options.virtual_port.as_deref(), Some("Blackbox"));
+    }
+
+    #[test]
+    fn invalid_osc_mapping_is_rejected() {
+        let options = config::Options {
+            osc_port: Some(8000),
+            osc_mappings: Some(vec!["/note pitch".to_string()]),
+            ..config().options(None).unwrap()
+        };
+        assert!(RecordingOptions::resolve(options).is_err());
+    }
+
+    #[test]
+    fn follow_transport_is_read_from_profile() {
+        let config = config::Config::parse(
+            r#"
+            archive_dir = "/archive"
+            ports = ["TD-17"]
+
+            [profiles.daw]
+            follow_transport = true
+            "#,
+        )
+        .unwrap();
+        let options = RecordingOptions::resolve(config.options(Some("daw")).unwrap()).unwrap();
+        assert!(options.policy.follow_transport);
+        let options = RecordingOptions::resolve(config.options(None).unwrap()).unwrap();
+        assert!(!options.policy.follow_transport);
+    }
+
+    #[test]
+    fn controls_are_parsed() {
+        let options = config::Options {
+            controls: Some(vec![
+                "split=cc80".to_string(),
+                "discard=note21+22".to_string(),
+            ]),
+            filter_controls: Some(true),
+            ..config().options(Some("drums")).unwrap()
+        };
+        let options = RecordingOptions::resolve(options).unwrap();
+        assert_eq!(options.controls.len(), 2);
+        assert!(options.filter_controls);
+        let options = config::Options {
+            controls: Some(vec!["split=cc".to_string()]),
+            ..config().options(Some("drums")).unwrap()
+        };
+        assert!(RecordingOptions::resolve(options).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::ports::record_message;
+use crate::recorder::{MidiSource, SharedSession};
+use crate::session::{data_length, SysExAssembler, MAX_SYSEX_LENGTH};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Multicast group of ipMIDI.
+pub const IPMIDI_GROUP: Ipv4Addr = Ipv4Addr::new(225, 0, 0, 37);
+/// UDP port of the first ipMIDI port, the following ones use the next port numbers.
+pub const IPMIDI_PORT: u16 = 21928;
+
+/// How often receiving threads check whether the listener is dropped.
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_millis(200);
+/// Senders of datagrams that send nothing for this long no longer count as connected.
+pub(crate) const SENDER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Splits a stream of MIDI bytes into messages, following running status.
+/// Real-time messages may come in the middle of other messages.
+#[derive(Default)]
+struct MidiStreamParser {
+    running_status: Option<u8>,
+    message: Vec<u8>,
+}
+
+impl MidiStreamParser {
+    /// Returns a message once its last byte is pushed.
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            0xF8..=0xFF => return Some(vec![byte]),
+            0xF7 if self.message.first() == Some(&0xF0) => {
+                self.message.push(byte);
+                return Some(std::mem::take(&mut self.message));
+            }
+            0xF7 => {
+                self.message.clear();
+                return None;
+            }
+            0x80..=0xF6 => {
+                self.running_status = (byte < 0xF0).then_some(byte);
+                self.message.clear();
+                self.message.push(byte);
+            }
+            _ if self.message.first() == Some(&0xF0) => {
+                if self.message.len() >= MAX_SYSEX_LENGTH {
+                    // The rest is dropped as data without a status byte.
+                    eprintln!(
+                        "Dropping SysEx message longer than {} bytes.",
+                        MAX_SYSEX_LENGTH
+                    );
+                    self.message = Vec::new();
+                } else {
+                    self.message.push(byte);
+                }
+                return None;
+            }
+            _ => {
+                if self.message.is_empty() {
+                    // Data without a status byte is dropped.
+                    self.message.push(self.running_status?);
+                }
+                self.message.push(byte);
+            }
+        }
+        let status = self.message[0];
+        (status != 0xF0 && self.message.len() > data_length(status))
+            .then(|| std::mem::take(&mut self.message))
+    }
+}
+
+/// State shared by a listener and its receiving threads.
+pub(crate) struct Endpoint {
+    /// Added to sender names to tell where the track comes from.
+    kind: &'static str,
+    /// Origin of event timestamps.
+    start_time: Instant,
+    pub(crate) record_timecode: bool,
+    pub(crate) stopped: AtomicBool,
+    /// Session track of each sender.
+    pub(crate) tracks: Mutex<HashMap<String, usize>>,
+}
+
+impl Endpoint {
+    pub(crate) fn new(kind: &'static str, record_timecode: bool) -> Self {
+        Endpoint {
+            kind,
+            start_time: Instant::now(),
+            record_timecode,
+            stopped: AtomicBool::new(false),
+            tracks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn track(&self, session: &SharedSession, sender: String) -> usize {
+        let mut tracks = self.tracks.lock().unwrap();
+        let name = format!("{} ({})", sender, self.kind);
+        *tracks
+            .entry(sender)
+            .or_insert_with(|| session.lock().unwrap().add_track(name))
+    }
+
+    pub(crate) fn timestamp(&self) -> u64 {
+        self.start_time.elapsed().as_micros() as u64
+    }
+
+    pub(crate) fn is_timeout(e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    }
+}
+
+/// Accepts TCP connections that send plain MIDI bytes, e.g. from scripts or
+/// serial-to-network adapters. Each connection is recorded to its own track.
+pub struct TcpMidiListener {
+    listener: Option<TcpListener>,
+    port: u16,
+    endpoint: Arc<Endpoint>,
+    /// Sender names of the open connections.
+    connections: Arc<Mutex<HashSet<String>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TcpMidiListener {
+    /// Listens on all interfaces, port 0 picks a free port, see [`TcpMidiListener::port`].
+    pub fn new(port: u16, record_timecode: bool) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpMidiListener {
+            port: listener.local_addr()?.port(),
+            listener: Some(listener),
+            endpoint: Arc::new(Endpoint::new("TCP", record_timecode)),
+            connections: Arc::new(Mutex::new(HashSet::new())),
+            thread: None,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn accept(
+        listener: TcpListener,
+        endpoint: Arc<Endpoint>,
+        connections: Arc<Mutex<HashSet<String>>>,
+        session: SharedSession,
+    ) {
+        let mut threads = Vec::new();
+        while !endpoint.stopped.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, from)) => {
+                    let endpoint = endpoint.clone();
+                    let connections = connections.clone();
+                    let session = session.clone();
+                    let name = Self::sender_name(&connections, from);
+                    threads.push(std::thread::spawn(move || {
+                        Self::receive(stream, from, &name, &endpoint, &session);
+                        connections.lock().unwrap().remove(&name);
+                    }));
+                }
+                Err(e) if Endpoint::is_timeout(&e) => std::thread::sleep(READ_TIMEOUT / 4),
+                Err(e) => {
+                    eprintln!("Cannot accept MIDI over TCP connection: {}", e);
+                    std::thread::sleep(READ_TIMEOUT);
+                }
+            }
+            threads.retain(|thread: &JoinHandle<()>| !thread.is_finished());
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+
+    /// Names the sender after its host, with a number while the host has other connections
+    /// open. A new connection after a restart continues the track of the one before.
+    fn sender_name(connections: &Mutex<HashSet<String>>, from: SocketAddr) -> String {
+        let mut connections = connections.lock().unwrap();
+        let name = (1..)
+            .map(|n| match n {
+                1 => from.ip().to_string(),
+                n => format!("{} #{}", from.ip(), n),
+            })
+            .find(|name| !connections.contains(name))
+            .unwrap();
+        connections.insert(name.clone());
+        name
+    }
+
+    fn receive(
+        mut stream: TcpStream,
+        from: SocketAddr,
+        name: &str,
+        endpoint: &Endpoint,
+        session: &SharedSession,
+    ) {
+        let track = endpoint.track(session, name.to_string());
+        println!("Connected MIDI over TCP from {}", from);
+        let setup = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT)));
+        if let Err(e) = setup {
+            eprintln!("Cannot read MIDI over TCP from {}: {}", from, e);
+            return;
+        }
+        let mut parser = MidiStreamParser::default();
+        let mut sysex = SysExAssembler::default();
+        let mut buffer = [0; 4096];
+        while !endpoint.stopped.load(Ordering::Relaxed) {
+            let length = match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(length) => length,
+                Err(e) if Endpoint::is_timeout(&e) => continue,
+                Err(e) => {
+                    eprintln!("Cannot read MIDI over TCP from {}: {}", from, e);
+                    break;
+                }
+            };
+            let timestamp = endpoint.timestamp();
+            for &byte in &buffer[..length] {
+                if let Some(message) = parser.push(byte) {
+                    record_message(
+                        session,
+                        track,
+                        name,
+                        &message,
+                        timestamp,
+                        &mut sysex,
+                        endpoint.record_timecode,
+                    );
+                }
+            }
+        }
+        println!("\nMIDI over TCP from {} disconnected.", from);
+        session
+            .lock()
+            .unwrap()
+            .release_held(track, endpoint.timestamp());
+    }
+}
+
+impl MidiSource for TcpMidiListener {
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        if let Some(listener) = self.listener.take() {
+            println!("Listening for MIDI over TCP on port {}", self.port);
+            let endpoint = self.endpoint.clone();
+            let connections = self.connections.clone();
+            let session = session.clone();
+            self.thread = Some(std::thread::spawn(move || {
+                Self::accept(listener, endpoint, connections, session)
+            }));
+        }
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    fn input_count(&self) -> usize {
+        self.endpoint.tracks.lock().unwrap().len()
+    }
+}
+
+impl Drop for TcpMidiListener {
+    fn drop(&mut self) {
+        self.endpoint.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A host that sends ipMIDI datagrams.
+struct IpMidiSender {
+    track: usize,
+    parser: MidiStreamParser,
+    sysex: SysExAssembler,
+    last_seen: Instant,
+}
+
+/// Receives ipMIDI, plain MIDI bytes in UDP multicast datagrams. Each sender address is
+/// recorded to its own track.
+pub struct IpMidiListener {
+    socket: Arc<UdpSocket>,
+    endpoint: Arc<Endpoint>,
+    senders: Arc<Mutex<HashMap<SocketAddr, IpMidiSender>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl IpMidiListener {
+    /// Joins the multicast group on the port, e.g. [`IPMIDI_GROUP`] and [`IPMIDI_PORT`].
+    /// Datagrams sent directly to the port are recorded as well.
+    pub fn new(group: Ipv4Addr, port: u16, record_timecode: bool) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(IpMidiListener {
+            socket: Arc::new(socket),
+            endpoint: Arc::new(Endpoint::new("ipMIDI", record_timecode)),
+            senders: Arc::new(Mutex::new(HashMap::new())),
+            thread: None,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map_or(0, |a| a.port())
+    }
+
+    fn receive(
+        socket: &UdpSocket,
+        endpoint: &Endpoint,
+        senders: &Mutex<HashMap<SocketAddr, IpMidiSender>>,
+        session: &SharedSession,
+    ) {
+        let mut buffer = [0; 65536];
+        while !endpoint.stopped.load(Ordering::Relaxed) {
+            let (length, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if Endpoint::is_timeout(&e) => continue,
+                Err(e) => {
+                    eprintln!("Cannot receive ipMIDI datagram: {}", e);
+                    std::thread::sleep(READ_TIMEOUT);
+                    continue;
+                }
+            };
+            let timestamp = endpoint.timestamp();
+            let mut senders = senders.lock().unwrap();
+            let sender = senders.entry(from).or_insert_with(|| {
+                println!("Receiving ipMIDI from {}", from);
+                IpMidiSender {
+                    track: endpoint.track(session, from.to_string()),
+                    parser: MidiStreamParser::default(),
+                    sysex: SysExAssembler::default(),
+                    last_seen: Instant::now(),
+                }
+            });
+            sender.last_seen = Instant::now();
+            let name = from.to_string();
+            for &byte in &buffer[..length] {
+                if let Some(message) = sender.parser.push(byte) {
+                    record_message(
+                        session,
+                        sender.track,
+                        &name,
+                        &message,
+                        timestamp,
+                        &mut sender.sysex,
+                        endpoint.record_timecode,
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl MidiSource for IpMidiListener {
+    /// Starts receiving, and releases notes of senders that have gone silent.
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        if self.thread.is_none() {
+            println!("Listening for ipMIDI on port {}", self.port());
+            let socket = self.socket.clone();
+            let endpoint = self.endpoint.clone();
+            let senders = self.senders.clone();
+            let session = session.clone();
+            self.thread = Some(std::thread::spawn(move || {
+                Self::receive(&socket, &endpoint, &senders, &session)
+            }));
+        }
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|address, sender| {
+            if sender.last_seen.elapsed() <= SENDER_TIMEOUT {
+                return true;
+            }
+            println!("\nipMIDI sender {} is silent.", address);
+            session
+                .lock()
+                .unwrap()
+                .release_held(sender.track, self.endpoint.timestamp());
+            false
+        });
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.senders.lock().unwrap().len()
+    }
+
+    fn input_count(&self) -> usize {
+        self.endpoint.tracks.lock().unwrap().len()
+    }
+}
+
+impl Drop for IpMidiListener {
+    fn drop(&mut self) {
+        self.endpoint.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_is_split_into_messages() {
+        let mut parser = MidiStreamParser::default();
+        let stream = [
+            0x90, 60, 100, 62,   // Running status
+            0xF8, // Clock in the middle of a message
+            90, 0xF0, 1, 2, 0xF7, 0xC0, 5, 7, 0xF6, // Tune request ends running status
+            0x40,
+        ];
+        let messages: Vec<_> = stream.iter().filter_map(|&b| parser.push(b)).collect();
+        assert_eq!(
+            messages,
+            vec![
+                vec![0x90, 60, 100],
+                vec![0xF8],
+                vec![0x90, 62, 90],
+                vec![0xF0, 1, 2, 0xF7],
+                vec![0xC0, 5],
+                vec![0xC0, 7],
+                vec![0xF6],
+            ]
+        );
+    }
+
+    #[test]
+    fn long_sysex_is_dropped() {
+        let mut parser = MidiStreamParser::default();
+        let stream = std::iter::once(0xF0)
+            .chain(std::iter::repeat_n(1, MAX_SYSEX_LENGTH))
+            .chain([0xF7, 0x90, 60, 100]);
+        let messages: Vec<_> = stream.filter_map(|b| parser.push(b)).collect();
+        assert_eq!(messages, vec![vec![0x90, 60, 100]]);
+    }
+}
@@ -0,0 +1,485 @@
+use crate::network::{Endpoint, READ_TIMEOUT, SENDER_TIMEOUT};
+use crate::ports::record_message;
+use crate::recorder::{MidiSource, SharedSession};
+use crate::session::{data_length, SysExAssembler};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// Part of a MIDI message that an OSC argument or a constant of a mapping gives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    /// 1 to 16.
+    Channel,
+    Pitch,
+    Velocity,
+    Controller,
+    Value,
+    Program,
+    Pressure,
+    Bend,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "channel" => Field::Channel,
+            "pitch" => Field::Pitch,
+            "velocity" => Field::Velocity,
+            "controller" => Field::Controller,
+            "value" => Field::Value,
+            "program" => Field::Program,
+            "pressure" => Field::Pressure,
+            "bend" => Field::Bend,
+            _ => return None,
+        })
+    }
+
+    fn range(self) -> (i64, i64) {
+        match self {
+            Field::Channel => (1, 16),
+            Field::Bend => (0, 0x3FFF),
+            _ => (0, 127),
+        }
+    }
+
+    /// Floats of continuous fields go from 0 to 1, as faders send them, and are scaled
+    /// to the range of the field.
+    fn value(self, argument: &OscArgument) -> Option<i64> {
+        let (min, max) = self.range();
+        let continuous = matches!(
+            self,
+            Field::Velocity | Field::Value | Field::Pressure | Field::Bend
+        );
+        let value = match *argument {
+            OscArgument::Int(value) => value,
+            OscArgument::Float(value) if continuous => (value * max as f64).round() as i64,
+            OscArgument::Float(value) => value.round() as i64,
+            OscArgument::Midi(_) | OscArgument::Other => return None,
+        };
+        Some(value.clamp(min, max))
+    }
+}
+
+/// Turns OSC messages sent to an address into MIDI messages. Written as the address
+/// followed by what each argument gives, and `field=value` for fixed parts, e.g.
+/// `/note channel pitch velocity` or `/1/fader1 controller=7 value`. Fields are
+/// `channel` (1 to 16, 1 if not given), `pitch`, `velocity`, `controller`, `value`,
+/// `program`, `pressure` and `bend`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMapping {
+    address: String,
+    arguments: Vec<Field>,
+    constants: Vec<(Field, i64)>,
+}
+
+impl FromStr for OscMapping {
+    type Err = String;
+
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+        let mut words = mapping.split_whitespace();
+        let address = match words.next() {
+            Some(address) if address.starts_with('/') => address.to_string(),
+            _ => {
+                return Err(format!(
+                    "OSC mapping '{}' does not start with an address",
+                    mapping
+                ))
+            }
+        };
+        let mut result = OscMapping {
+            address,
+            arguments: Vec::new(),
+            constants: Vec::new(),
+        };
+        for word in words {
+            let (name, constant) = match word.split_once('=') {
+                Some((name, value)) => {
+                    let value = value.parse::<i64>().map_err(|_| {
+                        format!("Invalid value '{}' in OSC mapping '{}'", value, mapping)
+                    })?;
+                    (name, Some(value))
+                }
+                None => (word, None),
+            };
+            let field = Field::parse(name)
+                .ok_or_else(|| format!("Unknown field '{}' in OSC mapping '{}'", name, mapping))?;
+            if result.fields().any(|f| f == field) {
+                return Err(format!(
+                    "Field '{}' repeats in OSC mapping '{}'",
+                    name, mapping
+                ));
+            }
+            match constant {
+                Some(value) => {
+                    let (min, max) = field.range();
+                    result.constants.push((field, value.clamp(min, max)));
+                }
+                None => result.arguments.push(field),
+            }
+        }
+        if result.status().is_none() {
+            return Err(format!(
+                "OSC mapping '{}' does not describe a MIDI message",
+                mapping
+            ));
+        }
+        Ok(result)
+    }
+}
+
+impl OscMapping {
+    fn fields(&self) -> impl Iterator<Item = Field> + '_ {
+        self.arguments
+            .iter()
+            .copied()
+            .chain(self.constants.iter().map(|c| c.0))
+    }
+
+    /// Status byte without the channel, from the fields that are given.
+    fn status(&self) -> Option<u8> {
+        let mut fields: Vec<Field> = self.fields().filter(|&f| f != Field::Channel).collect();
+        fields.sort_by_key(|&f| f as u8);
+        Some(match fields[..] {
+            [Field::Pitch, Field::Velocity] => 0x90,
+            [Field::Pitch, Field::Pressure] => 0xA0,
+            [Field::Controller, Field::Value] => 0xB0,
+            [Field::Program] => 0xC0,
+            [Field::Pressure] => 0xD0,
+            [Field::Bend] => 0xE0,
+            _ => return None,
+        })
+    }
+
+    /// MIDI message for the arguments of a message sent to the address.
+    fn message(&self, arguments: &[OscArgument]) -> Option<Vec<u8>> {
+        let mut values: Vec<(Field, i64)> = self.constants.clone();
+        for (&field, argument) in self.arguments.iter().zip(arguments) {
+            values.push((field, field.value(argument)?));
+        }
+        if values.len() < self.arguments.len() + self.constants.len() {
+            return None;
+        }
+        let get = |field| values.iter().find(|v| v.0 == field).map(|v| v.1 as u8);
+        let channel = get(Field::Channel).unwrap_or(1) - 1;
+        let status = self.status()?;
+        Some(match status {
+            0x90 if get(Field::Velocity) == Some(0) => vec![0x80 | channel, get(Field::Pitch)?, 0],
+            0x90 => vec![0x90 | channel, get(Field::Pitch)?, get(Field::Velocity)?],
+            0xA0 => vec![0xA0 | channel, get(Field::Pitch)?, get(Field::Pressure)?],
+            0xB0 => vec![0xB0 | channel, get(Field::Controller)?, get(Field::Value)?],
+            0xC0 => vec![0xC0 | channel, get(Field::Program)?],
+            0xD0 => vec![0xD0 | channel, get(Field::Pressure)?],
+            _ => {
+                let bend = values.iter().find(|v| v.0 == Field::Bend)?.1;
+                vec![0xE0 | channel, (bend & 0x7F) as u8, (bend >> 7) as u8]
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum OscArgument {
+    Int(i64),
+    Float(f64),
+    /// Port, status and two data bytes.
+    Midi([u8; 4]),
+    /// Arguments that cannot become part of a MIDI message.
+    Other,
+}
+
+#[derive(Debug, PartialEq)]
+struct OscMessage {
+    address: String,
+    arguments: Vec<OscArgument>,
+}
+
+/// Reads the messages of an OSC packet, bundles included. Time tags are ignored,
+/// messages are recorded when they arrive.
+fn parse_packet(packet: &[u8], messages: &mut Vec<OscMessage>) -> Option<()> {
+    if let Some(mut elements) = packet.strip_prefix(b"#bundle\0") {
+        elements = elements.get(8..)?;
+        while !elements.is_empty() {
+            let size = u32::from_be_bytes(elements.get(..4)?.try_into().ok()?) as usize;
+            parse_packet(elements.get(4..4 + size)?, messages)?;
+            elements = &elements[4 + size..];
+        }
+        return Some(());
+    }
+    let mut data = packet;
+    let address = read_string(&mut data)?;
+    // Type tags may be missing in messages of old implementations, such have no arguments.
+    let tags = if data.is_empty() {
+        String::new()
+    } else {
+        read_string(&mut data)?
+    };
+    let mut arguments = Vec::new();
+    for tag in tags.strip_prefix(',').unwrap_or_default().chars() {
+        let data = &mut data;
+        arguments.push(match tag {
+            'i' => OscArgument::Int(i32::from_be_bytes(take::<4>(data)?) as i64),
+            'h' => OscArgument::Int(i64::from_be_bytes(take::<8>(data)?)),
+            'f' => OscArgument::Float(f32::from_be_bytes(take::<4>(data)?) as f64),
+            'd' => OscArgument::Float(f64::from_be_bytes(take::<8>(data)?)),
+            'm' => OscArgument::Midi(take(data)?),
+            'T' => OscArgument::Int(i64::MAX),
+            'F' => OscArgument::Int(0),
+            'c' | 'r' => take::<4>(data).map(|_| OscArgument::Other)?,
+            't' => take::<8>(data).map(|_| OscArgument::Other)?,
+            's' | 'S' => read_string(data).map(|_| OscArgument::Other)?,
+            'b' => {
+                let size = u32::from_be_bytes(take::<4>(data)?) as usize;
+                *data = data.get(size.div_ceil(4) * 4..)?;
+                OscArgument::Other
+            }
+            _ => OscArgument::Other,
+        });
+    }
+    messages.push(OscMessage { address, arguments });
+    Some(())
+}
+
+fn take<const N: usize>(data: &mut &[u8]) -> Option<[u8; N]> {
+    let bytes = data.get(..N)?.try_into().ok()?;
+    *data = &data[N..];
+    Some(bytes)
+}
+
+/// Reads a null-terminated string padded to 4 bytes.
+fn read_string(data: &mut &[u8]) -> Option<String> {
+    let end = data.iter().position(|&b| b == 0)?;
+    let string = String::from_utf8_lossy(&data[..end]).into_owned();
+    *data = data.get((end + 4) / 4 * 4..)?;
+    Some(string)
+}
+
+/// MIDI messages of an OSC message: those of `m` arguments, or what a mapping of the address
+/// makes of the arguments.
+fn midi_messages(message: &OscMessage, mappings: &[OscMapping]) -> Vec<Vec<u8>> {
+    let mut result = Vec::new();
+    for mapping in mappings.iter().filter(|m| m.address == message.address) {
+        result.extend(mapping.message(&message.arguments));
+    }
+    for argument in &message.arguments {
+        if let OscArgument::Midi([_, status, data @ ..]) = *argument {
+            if status >= 0x80 && status != 0xF0 {
+                result.push([&[status], &data[..data_length(status)]].concat());
+            }
+        }
+    }
+    result
+}
+
+/// A host that sends OSC messages.
+struct OscSender {
+    track: usize,
+    sysex: SysExAssembler,
+    last_seen: Instant,
+}
+
+/// OSC server, e.g. for TouchOSC layouts or SuperCollider. Records MIDI messages of the
+/// `m` type tag and OSC messages that mappings turn into MIDI. Each sender address is
+/// recorded to its own track.
+pub struct OscListener {
+    socket: Arc<UdpSocket>,
+    mappings: Arc<Vec<OscMapping>>,
+    endpoint: Arc<Endpoint>,
+    senders: Arc<Mutex<HashMap<SocketAddr, OscSender>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OscListener {
+    /// Listens on the UDP port on all interfaces, port 0 picks a free port.
+    pub fn new(port: u16, mappings: Vec<OscMapping>, record_timecode: bool) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(OscListener {
+            socket: Arc::new(socket),
+            mappings: Arc::new(mappings),
+            endpoint: Arc::new(Endpoint::new("OSC", record_timecode)),
+            senders: Arc::new(Mutex::new(HashMap::new())),
+            thread: None,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map_or(0, |a| a.port())
+    }
+
+    fn receive(
+        socket: &UdpSocket,
+        mappings: &[OscMapping],
+        endpoint: &Endpoint,
+        senders: &Mutex<HashMap<SocketAddr, OscSender>>,
+        session: &SharedSession,
+    ) {
+        let mut buffer = [0; 65536];
+        while !endpoint.stopped.load(Ordering::Relaxed) {
+            let (length, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if Endpoint::is_timeout(&e) => continue,
+                Err(e) => {
+                    eprintln!("Cannot receive OSC packet: {}", e);
+                    std::thread::sleep(READ_TIMEOUT);
+                    continue;
+                }
+            };
+            let timestamp = endpoint.timestamp();
+            let mut messages = Vec::new();
+            if parse_packet(&buffer[..length], &mut messages).is_none() {
+                eprintln!("Ignoring malformed OSC packet from {}", from);
+            }
+            let midi: Vec<_> = messages
+                .iter()
+                .flat_map(|m| midi_messages(m, mappings))
+                .collect();
+            let mut senders = senders.lock().unwrap();
+            // Pings and other messages keep a sender connected, but only MIDI gives it a track.
+            if midi.is_empty() {
+                if let Some(sender) = senders.get_mut(&from) {
+                    sender.last_seen = Instant::now();
+                }
+                continue;
+            }
+            let sender = senders.entry(from).or_insert_with(|| {
+                println!("Receiving OSC from {}", from);
+                OscSender {
+                    track: endpoint.track(session, from.to_string()),
+                    sysex: SysExAssembler::default(),
+                    last_seen: Instant::now(),
+                }
+            });
+            sender.last_seen = Instant::now();
+            let name = from.to_string();
+            for midi in &midi {
+                record_message(
+                    session,
+                    sender.track,
+                    &name,
+                    midi,
+                    timestamp,
+                    &mut sender.sysex,
+                    endpoint.record_timecode,
+                );
+            }
+        }
+    }
+}
+
+impl MidiSource for OscListener {
+    /// Starts receiving, and releases notes of senders that have gone silent.
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        if self.thread.is_none() {
+            println!("Listening for OSC on port {}", self.port());
+            let socket = self.socket.clone();
+            let mappings = self.mappings.clone();
+            let endpoint = self.endpoint.clone();
+            let senders = self.senders.clone();
+            let session = session.clone();
+            self.thread = Some(std::thread::spawn(move || {
+                Self::receive(&socket, &mappings, &endpoint, &senders, &session)
+            }));
+        }
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|address, sender| {
+            if sender.last_seen.elapsed() <= SENDER_TIMEOUT {
+                return true;
+            }
+            println!("\nOSC sender {} is silent.", address);
+            session
+                .lock()
+                .unwrap()
+                .release_held(sender.track, self.endpoint.timestamp());
+            false
+        });
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.senders.lock().unwrap().len()
+    }
+
+    fn input_count(&self) -> usize {
+        self.endpoint.tracks.lock().unwrap().len()
+    }
+}
+
+impl Drop for OscListener {
+    fn drop(&mut self) {
+        self.endpoint.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_turn_messages_into_midi() {
+        let note: OscMapping = "/note channel pitch velocity".parse().unwrap();
+        let fader: OscMapping = "/1/fader1 channel=2 controller=7 value".parse().unwrap();
+        let bend: OscMapping = "/bend bend".parse().unwrap();
+        let mappings = [note, fader, bend];
+        let message = |address: &str, arguments| OscMessage {
+            address: address.to_string(),
+            arguments,
+        };
+        use OscArgument::*;
+        let cases = [
+            (
+                message("/note", vec![Int(10), Int(60), Int(100)]),
+                vec![0x99, 60, 100],
+            ),
+            (
+                message("/note", vec![Int(1), Float(60.0), Int(0)]),
+                vec![0x80, 60, 0],
+            ),
+            (message("/1/fader1", vec![Float(0.5)]), vec![0xB1, 7, 64]),
+            (message("/bend", vec![Float(1.0)]), vec![0xE0, 0x7F, 0x7F]),
+            (message("/midi", vec![Midi([0, 0xC3, 5, 0])]), vec![0xC3, 5]),
+        ];
+        for (message, midi) in cases {
+            assert_eq!(
+                midi_messages(&message, &mappings),
+                vec![midi],
+                "{:?}",
+                message
+            );
+        }
+        // Missing arguments.
+        assert!(midi_messages(&message("/note", vec![Int(1)]), &mappings).is_empty());
+        assert!("/note pitch".parse::<OscMapping>().is_err());
+        assert!("note pitch velocity".parse::<OscMapping>().is_err());
+        assert!("/note pitch velocity velocity"
+            .parse::<OscMapping>()
+            .is_err());
+        assert!("/note pitch veloctiy".parse::<OscMapping>().is_err());
+    }
+
+    #[test]
+    fn bundles_are_unpacked() {
+        let message = b"/midi\0\0\0,m\0\0\0\x90\x3C\x64";
+        let mut packet = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+        packet.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        packet.extend_from_slice(message);
+        let mut messages = Vec::new();
+        assert_eq!(parse_packet(&packet, &mut messages), Some(()));
+        assert_eq!(
+            messages,
+            vec![OscMessage {
+                address: "/midi".to_string(),
+                arguments: vec![OscArgument::Midi([0, 0x90, 0x3C, 0x64])],
+            }]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use crate::recorder::{MidiSource, SharedSession};
+use crate::session::SysExAssembler;
+use crate::PACKAGE_NAME;
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+use midly::live::{LiveEvent, SystemRealtime};
+use std::error::Error;
+use std::time::Instant;
+
+/// Which MIDI input ports to record from.
+pub enum PortSelection {
+    /// First port matching each of the name prefixes.
+    Prefixes(Vec<String>),
+    All,
+}
+
+/// Names of the available MIDI input ports.
+pub fn input_port_names() -> Result<Vec<String>, Box<dyn Error>> {
+    let midi_input = MidiInput::new(PACKAGE_NAME)?;
+    let mut names = Vec::new();
+    for port in midi_input.ports() {
+        names.push(midi_input.port_name(&port)?);
+    }
+    Ok(names)
+}
+
+/// An input that is recorded to a session track, possibly not connected at the moment.
+struct PortSlot {
+    /// Port name prefix, or the full port name when recording all ports.
+    pattern: String,
+    track: usize,
+    connection: Option<(MidiInputPort, MidiInputConnection<SysExAssembler>)>,
+}
+
+/// Keeps the selected MIDI inputs connected, picking up ports that appear or come back
+/// after the device is unplugged or powered off.
+pub struct PortWatcher {
+    selection: PortSelection,
+    /// Used to enumerate ports.
+    midi_input: MidiInput,
+    /// Common origin of event timestamps.
+    start_time: Instant,
+    record_timecode: bool,
+    slots: Vec<PortSlot>,
+}
+
+impl PortWatcher {
+    /// Set `record_timecode` to also record MIDI Time Code quarter frames.
+    pub fn new(selection: PortSelection, record_timecode: bool) -> Result<Self, Box<dyn Error>> {
+        Ok(PortWatcher {
+            selection,
+            midi_input: MidiInput::new(PACKAGE_NAME)?,
+            start_time: Instant::now(),
+            record_timecode,
+            slots: Vec::new(),
+        })
+    }
+}
+
+impl MidiSource for PortWatcher {
+    /// Drops connections to ports that have gone, and connects ports that are available.
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        if let (PortSelection::Prefixes(prefixes), true) = (&self.selection, self.slots.is_empty())
+        {
+            for prefix in prefixes {
+                let pattern = prefix.trim().to_string();
+                let track = session.lock().unwrap().add_track(pattern.clone());
+                self.slots.push(PortSlot {
+                    pattern,
+                    track,
+                    connection: None,
+                });
+            }
+        }
+
+        let mut available = Vec::new();
+        for port in self.midi_input.ports() {
+            let name = self.midi_input.port_name(&port)?;
+            available.push((port, name));
+        }
+
+        for slot in &mut self.slots {
+            let Some((port, _)) = &slot.connection else {
+                continue;
+            };
+            if !available.iter().any(|(p, _)| p == port) {
+                println!("\nMIDI input '{}' disconnected.", slot.pattern);
+                slot.connection = None;
+                let timestamp = self.start_time.elapsed().as_micros() as u64;
+                session.lock().unwrap().release_held(slot.track, timestamp);
+            }
+        }
+
+        if let PortSelection::All = self.selection {
+            for (_, name) in &available {
+                if !self.slots.iter().any(|slot| &slot.pattern == name) {
+                    let track = session.lock().unwrap().add_track(name.clone());
+                    self.slots.push(PortSlot {
+                        pattern: name.clone(),
+                        track,
+                        connection: None,
+                    });
+                }
+            }
+        }
+
+        for i in 0..self.slots.len() {
+            if self.slots[i].connection.is_some() {
+                continue;
+            }
+            let exact = matches!(self.selection, PortSelection::All);
+            let candidate = available.iter().find(|(port, name)| {
+                let matching = if exact {
+                    name == &self.slots[i].pattern
+                } else {
+                    name.starts_with(&self.slots[i].pattern)
+                };
+                matching
+                    && !self
+                        .slots
+                        .iter()
+                        .any(|slot| matches!(&slot.connection, Some((p, _)) if p == port))
+            });
+            if let Some((port, name)) = candidate {
+                let track = self.slots[i].track;
+                let connection = connect_port(
+                    track,
+                    port,
+                    name,
+                    session.clone(),
+                    self.start_time,
+                    self.record_timecode,
+                )?;
+                println!("Connected MIDI input: '{}'", name);
+                session.lock().unwrap().set_track_name(track, name.clone());
+                self.slots[i].connection = Some((port.clone(), connection));
+            }
+        }
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.connection.is_some())
+            .count()
+    }
+
+    fn input_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn missing_input(&self) -> Option<String> {
+        self.slots
+            .iter()
+            .find(|slot| slot.connection.is_none())
+            .map(|slot| slot.pattern.clone())
+    }
+}
+
+/// Starts recording events of the port into the session's track with the given index.
+fn connect_port(
+    track: usize,
+    port: &MidiInputPort,
+    port_name: &str,
+    session: SharedSession,
+    start_time: Instant,
+    record_timecode: bool,
+) -> Result<MidiInputConnection<SysExAssembler>, Box<dyn Error>> {
+    let mut midi_input = MidiInput::new(PACKAGE_NAME)?;
+    midi_input.ignore(Ignore::None);
+    let connection = midi_input.connect(
+        port,
+        PACKAGE_NAME,
+        recording_callback(track, port_name, session, start_time, record_timecode),
+        SysExAssembler::default(),
+    )?;
+    Ok(connection)
+}
+
+/// Handles messages of a midir connection.
+fn recording_callback(
+    track: usize,
+    port_name: &str,
+    session: SharedSession,
+    start_time: Instant,
+    record_timecode: bool,
+) -> impl FnMut(u64, &[u8], &mut SysExAssembler) + Send + 'static {
+    // Backend timestamps count from the connection time, bring them to a common origin.
+    let connect_offset = start_time.elapsed().as_micros() as u64;
+    let name = port_name.to_string();
+    move |timestamp, message, sysex| {
+        let timestamp = common_timestamp(timestamp, connect_offset, start_time);
+        record_message(
+            &session,
+            track,
+            &name,
+            message,
+            timestamp,
+            sysex,
+            record_timecode,
+        );
+    }
+}
+
+/// Microseconds since `start_time` of a backend timestamp of a port connected `connect_offset`
+/// microseconds after it.
+fn common_timestamp(timestamp: u64, connect_offset: u64, start_time: Instant) -> u64 {
+    // Some backends do not provide timestamps, use wall clock for those.
+    if timestamp == 0 {
+        start_time.elapsed().as_micros() as u64
+    } else {
+        connect_offset + timestamp
+    }
+}
+
+/// Records a message received from an input into its track, unless it is filtered out.
+pub(crate) fn record_message(
+    session: &SharedSession,
+    track: usize,
+    input_name: &str,
+    message: &[u8],
+    timestamp: u64,
+    sysex: &mut SysExAssembler,
+    record_timecode: bool,
+) {
+    let Some(&status) = message.first() else {
+        return;
+    };
+    // Skip active sensing
+    if status == 0xFE {
+        return;
+    }
+    // MIDI clock is sent 24 times a beat, it only sets the tempo.
+    if status == 0xF8 {
+        let clock = LiveEvent::Realtime(SystemRealtime::TimingClock);
+        session.lock().unwrap().add_event(track, clock, timestamp);
+        return;
+    }
+    // MIDI time code is sent 100 times a second while a source is rolling.
+    if status == 0xF1 && !record_timecode {
+        return;
+    }
+    let Some(message) = sysex.push(message) else {
+        return;
+    };
+
+    if let Ok(live_event) = LiveEvent::parse(&message) {
+        println!("{} @ {}: {:?}", input_name, timestamp, live_event);
+
+        let mut session = session.lock().unwrap();
+        session.add_event(track, live_event, timestamp);
+    }
+}
+
+/// Input port that other applications connect to, e.g. with `aconnect`.
+/// It exists as long as the source does.
+#[cfg(unix)]
+pub struct VirtualPort {
+    name: String,
+    record_timecode: bool,
+    start_time: Instant,
+    track: Option<usize>,
+    connection: Option<MidiInputConnection<SysExAssembler>>,
+}
+
+#[cfg(unix)]
+impl VirtualPort {
+    pub fn new(name: String, record_timecode: bool) -> Self {
+        VirtualPort {
+            name,
+            record_timecode,
+            start_time: Instant::now(),
+            track: None,
+            connection: None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl MidiSource for VirtualPort {
+    /// Creates the port on the first call.
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        use midir::os::unix::VirtualInput;
+
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        let track = *self
+            .track
+            .get_or_insert_with(|| session.lock().unwrap().add_track(self.name.clone()));
+        let mut midi_input = MidiInput::new(PACKAGE_NAME)?;
+        midi_input.ignore(Ignore::None);
+        let connection = midi_input
+            .create_virtual(
+                &self.name,
+                recording_callback(
+                    track,
+                    &self.name,
+                    session.clone(),
+                    self.start_time,
+                    self.record_timecode,
+                ),
+                SysExAssembler::default(),
+            )
+            .map_err(|e| format!("Cannot create virtual MIDI input '{}': {}", self.name, e))?;
+        println!("Created virtual MIDI input '{}'", self.name);
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.connection.iter().count()
+    }
+
+    fn input_count(&self) -> usize {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn backend_timestamps_are_moved_to_common_origin() {
+        let start_time = Instant::now() - Duration::from_secs(2);
+        assert_eq!(common_timestamp(500, 1_000_000, start_time), 1_000_500);
+        // Without a backend timestamp the time since start is used.
+        let timestamp = common_timestamp(0, 1_000_000, start_time);
+        assert!((2_000_000..3_000_000).contains(&timestamp));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::clock::{Clock, SystemClock};
+use crate::controls::{ControlAction, Controls};
+use crate::session::{RecordingSession, SplitPolicy};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Session shared between the recorder and the threads that deliver events.
+pub type SharedSession = Arc<Mutex<RecordingSession>>;
+
+/// Delivers MIDI events into the recording session.
+pub trait MidiSource {
+    /// Called by the recorder about once a second, e.g. to connect inputs that have appeared.
+    /// Events can be added to the session here or from other threads at any time.
+    /// `now` is the time of the recorder's clock.
+    fn poll(&mut self, session: &SharedSession, now: Instant) -> Result<(), Box<dyn Error>>;
+
+    /// Number of inputs that are connected now.
+    fn connected_count(&self) -> usize;
+
+    /// Number of inputs that are recorded, connected or not.
+    fn input_count(&self) -> usize;
+
+    /// Name of an expected input that is not connected, if any.
+    fn missing_input(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<S: MidiSource + ?Sized> MidiSource for Box<S> {
+    fn poll(&mut self, session: &SharedSession, now: Instant) -> Result<(), Box<dyn Error>> {
+        (**self).poll(session, now)
+    }
+
+    fn connected_count(&self) -> usize {
+        (**self).connected_count()
+    }
+
+    fn input_count(&self) -> usize {
+        (**self).input_count()
+    }
+
+    fn missing_input(&self) -> Option<String> {
+        (**self).missing_input()
+    }
+}
+
+/// Receives finished takes.
+pub trait TakeSink {
+    /// Stores the current take of the session, which then starts a new one.
+    /// Returns false if the take is kept in the session to be retried later.
+    fn save(&mut self, session: &mut RecordingSession, now: Instant) -> bool;
+
+    /// Whether saving should be attempted now, false while waiting to retry.
+    fn is_ready(&self, _now: Instant) -> bool {
+        true
+    }
+
+    /// Cancels waiting before the next save attempt, e.g. after the storage is fixed.
+    fn retry_now(&mut self) {}
+
+    /// Removes the take saved last, returns false if there is none.
+    fn discard_last(&mut self) -> bool {
+        false
+    }
+
+    /// Marks the take saved last as a keeper, returns false if there is none.
+    fn keep_last(&mut self) -> bool {
+        false
+    }
+}
+
+/// Current state of a recorder, e.g. for status reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub connected_inputs: usize,
+    pub inputs: usize,
+    /// Events in the take in progress.
+    pub take_events: usize,
+}
+
+pub struct RecorderBuilder {
+    source: Box<dyn MidiSource>,
+    sink: Box<dyn TakeSink>,
+    policy: SplitPolicy,
+    journal_directory: Option<PathBuf>,
+    controls: Option<Controls>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RecorderBuilder {
+    pub fn policy(mut self, policy: SplitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Replaces the system clock, e.g. with a [`crate::clock::ManualClock`] in tests.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Journals takes in the directory, see [`crate::archive::recover_journals`].
+    pub fn journal(mut self, directory: PathBuf) -> Self {
+        self.journal_directory = Some(directory);
+        self
+    }
+
+    /// Lets MIDI controls split, discard or keep takes.
+    pub fn controls(mut self, controls: Controls) -> Self {
+        self.controls = Some(controls);
+        self
+    }
+
+    pub fn build(self) -> Recorder {
+        let mut session =
+            RecordingSession::new(self.policy, Vec::new()).with_clock(self.clock.clone());
+        if let Some(directory) = self.journal_directory {
+            session = session.with_journal(directory);
+        }
+        if let Some(controls) = self.controls {
+            session = session.with_controls(controls);
+        }
+        Recorder {
+            source: self.source,
+            sink: self.sink,
+            session: Arc::new(Mutex::new(session)),
+            clock: self.clock,
+        }
+    }
+}
+
+/// Records events of the source and passes each take to the sink once it is over.
+/// The owner calls [`Recorder::poll`] periodically and [`Recorder::finish`] at the end.
+pub struct Recorder {
+    source: Box<dyn MidiSource>,
+    sink: Box<dyn TakeSink>,
+    session: SharedSession,
+    clock: Arc<dyn Clock>,
+}
+
+impl Recorder {
+    pub fn builder(
+        source: impl MidiSource + 'static,
+        sink: impl TakeSink + 'static,
+    ) -> RecorderBuilder {
+        RecorderBuilder {
+            source: Box::new(source),
+            sink: Box::new(sink),
+            policy: SplitPolicy::default(),
+            journal_directory: None,
+            controls: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn session(&self) -> &SharedSession {
+        &self.session
+    }
+
+    pub fn source(&self) -> &dyn MidiSource {
+        self.source.as_ref()
+    }
+
+    /// Updates inputs and saves the take when the split policy says it is over.
+    /// Should be called about once a second.
+    pub fn poll(&mut self) -> Result<(), Box<dyn Error>> {
+        let now = self.clock.now();
+        let result = self.source.poll(&self.session, now);
+        // Do not hold up event delivery, the next poll will do.
+        if let Ok(mut session) = self.session.try_lock() {
+            if session.split_due(now) && self.sink.is_ready(now) {
+                self.sink.save(&mut session, now);
+            }
+            for action in session.take_control_requests() {
+                let done = if action == ControlAction::Keep {
+                    self.sink.keep_last()
+                } else {
+                    self.sink.discard_last()
+                };
+                if !done {
+                    eprintln!("No saved take to {:?}.", action);
+                }
+            }
+            session.sync_journal();
+        }
+        result
+    }
+
+    /// Saves the take in progress right away, returns false if it could not be saved.
+    pub fn split(&mut self) -> bool {
+        self.sink
+            .save(&mut self.session.lock().unwrap(), self.clock.now())
+    }
+
+    pub fn set_policy(&self, policy: SplitPolicy) {
+        self.session.lock().unwrap().set_policy(policy);
+    }
+
+    /// See [`TakeSink::retry_now`].
+    pub fn retry_now(&mut self) {
+        self.sink.retry_now();
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            connected_inputs: self.source.connected_count(),
+            inputs: self.source.input_count(),
+            take_events: self.session.lock().unwrap().event_count(),
+        }
+    }
+
+    /// Disconnects the inputs and saves the last take, returns false if it could not be saved.
+    pub fn finish(mut self) -> bool {
+        drop(self.source);
+        let mut session = self.session.lock().unwrap();
+        // A take that follows the transport may start as the previous one is saved.
+        while self.sink.save(&mut session, self.clock.now()) {
+            if session.event_count() == 0 {
+                return true;
+            }
+        }
+        false
+    }
+}
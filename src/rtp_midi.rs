@@ -0,0 +1,894 @@
+use crate::ports::record_message;
+use crate::recorder::{MidiSource, SharedSession};
+use crate::session::{data_length, SysExAssembler};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Control port that AppleMIDI peers use by default, the data port is the next one.
+pub const DEFAULT_RTP_MIDI_PORT: u16 = 5004;
+
+const PROTOCOL_VERSION: u32 = 2;
+/// RTP payload type of MIDI.
+const PAYLOAD_TYPE: u8 = 0x61;
+/// Peers send clock synchronization at least this often, silent ones are dropped.
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often receiving threads check whether the listener is dropped.
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+/// Receiver feedback lets the sender trim its recovery journal.
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Listens for RTP-MIDI (AppleMIDI) sessions, e.g. from the macOS Audio MIDI Setup or an iPad.
+/// Each peer name gets its own track. Lost packets are recovered from the journal that
+/// peers send along, so notes are not left hanging on a lossy Wi-Fi.
+pub struct RtpMidiListener {
+    endpoint: Arc<Endpoint>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl RtpMidiListener {
+    /// Binds the control port and the data port after it on all interfaces.
+    /// With port 0 a free pair of ports is picked, see [`RtpMidiListener::port`].
+    /// `name` is the session name that peers see.
+    pub fn new(name: String, port: u16, record_timecode: bool) -> io::Result<Self> {
+        let (control, data) = bind_ports(port)?;
+        control.set_read_timeout(Some(READ_TIMEOUT))?;
+        data.set_read_timeout(Some(READ_TIMEOUT))?;
+        // The SSRC only has to differ from the peers' ones.
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        Ok(RtpMidiListener {
+            endpoint: Arc::new(Endpoint {
+                name,
+                ssrc: nanos ^ std::process::id().rotate_left(16),
+                control,
+                data,
+                start_time: Instant::now(),
+                record_timecode,
+                stopped: AtomicBool::new(false),
+                peers: Mutex::new(Peers::default()),
+            }),
+            threads: Vec::new(),
+        })
+    }
+
+    /// Control port, the data port is the next one.
+    pub fn port(&self) -> u16 {
+        self.endpoint.control.local_addr().map_or(0, |a| a.port())
+    }
+}
+
+impl MidiSource for RtpMidiListener {
+    /// Starts receiving, and drops peers that have gone silent.
+    fn poll(&mut self, session: &SharedSession, _now: Instant) -> Result<(), Box<dyn Error>> {
+        if self.threads.is_empty() {
+            println!(
+                "Listening for RTP-MIDI sessions on port {} as '{}'",
+                self.port(),
+                self.endpoint.name
+            );
+            for data in [false, true] {
+                let endpoint = self.endpoint.clone();
+                let session = session.clone();
+                self.threads
+                    .push(std::thread::spawn(move || endpoint.receive(data, &session)));
+            }
+        }
+        let mut peers = self.endpoint.peers.lock().unwrap();
+        let silent: Vec<u32> = peers
+            .sessions
+            .iter()
+            .filter(|(_, peer)| peer.last_seen.elapsed() > PEER_TIMEOUT)
+            .map(|(ssrc, _)| *ssrc)
+            .collect();
+        for ssrc in silent {
+            let peer = peers.sessions.remove(&ssrc).unwrap();
+            println!("\nRTP-MIDI peer '{}' timed out.", peer.name);
+            peer.release_held(session);
+        }
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        let peers = self.endpoint.peers.lock().unwrap();
+        peers
+            .sessions
+            .values()
+            .filter(|peer| peer.data_address.is_some())
+            .count()
+    }
+
+    fn input_count(&self) -> usize {
+        self.endpoint.peers.lock().unwrap().tracks.len()
+    }
+}
+
+impl Drop for RtpMidiListener {
+    /// Ends the sessions so peers do not wait for the listener to come back.
+    fn drop(&mut self) {
+        self.endpoint.stopped.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        let peers = self.endpoint.peers.lock().unwrap();
+        for peer in peers.sessions.values() {
+            let bye = SessionCommand::Bye {
+                token: peer.token,
+                ssrc: self.endpoint.ssrc,
+            };
+            let _ = self
+                .endpoint
+                .control
+                .send_to(&bye.encode(), peer.control_address);
+        }
+    }
+}
+
+fn bind_ports(port: u16) -> io::Result<(UdpSocket, UdpSocket)> {
+    let bind = |port| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port));
+    if port != 0 {
+        return Ok((bind(port)?, bind(port.wrapping_add(1))?));
+    }
+    let mut attempts = 0;
+    loop {
+        let control = bind(0)?;
+        let control_port = control.local_addr()?.port();
+        match bind(control_port.wrapping_add(1)) {
+            Ok(data) if control_port != u16::MAX => return Ok((control, data)),
+            Err(e) if attempts >= 10 => return Err(e),
+            _ => attempts += 1,
+        }
+    }
+}
+
+/// State shared by the listener and its receiving threads.
+struct Endpoint {
+    name: String,
+    ssrc: u32,
+    control: UdpSocket,
+    data: UdpSocket,
+    /// Origin of event timestamps and of the session clock.
+    start_time: Instant,
+    record_timecode: bool,
+    stopped: AtomicBool,
+    peers: Mutex<Peers>,
+}
+
+#[derive(Default)]
+struct Peers {
+    /// Sessions by the peer's SSRC.
+    sessions: HashMap<u32, Peer>,
+    /// Session track of each peer name, a peer that comes back continues its track.
+    tracks: HashMap<String, usize>,
+}
+
+impl Endpoint {
+    fn receive(&self, data: bool, session: &SharedSession) {
+        let socket = if data { &self.data } else { &self.control };
+        let mut buffer = [0; 65536];
+        while !self.stopped.load(Ordering::Relaxed) {
+            match socket.recv_from(&mut buffer) {
+                Ok((length, from)) => self.handle_packet(&buffer[..length], from, data, session),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    eprintln!("Cannot receive RTP-MIDI packet: {}", e);
+                    std::thread::sleep(READ_TIMEOUT);
+                }
+            }
+        }
+    }
+
+    /// Session clock in units of 100 µs.
+    fn clock(&self) -> u64 {
+        self.start_time.elapsed().as_micros() as u64 / 100
+    }
+
+    fn handle_packet(&self, packet: &[u8], from: SocketAddr, data: bool, session: &SharedSession) {
+        let socket = if data { &self.data } else { &self.control };
+        let mut guard = self.peers.lock().unwrap();
+        let peers = &mut *guard;
+        let Some(command) = SessionCommand::parse(packet) else {
+            if data {
+                self.handle_rtp(packet, peers, session);
+            }
+            return;
+        };
+        match command {
+            SessionCommand::Invitation { token, ssrc, name } if !data => {
+                let track = *peers.tracks.entry(name.clone()).or_insert_with(|| {
+                    session
+                        .lock()
+                        .unwrap()
+                        .add_track(format!("{} (RTP-MIDI)", name))
+                });
+                if let Some(peer) = peers.sessions.remove(&ssrc) {
+                    peer.release_held(session);
+                }
+                peers
+                    .sessions
+                    .insert(ssrc, Peer::new(name, token, from, track));
+                self.reply(
+                    socket,
+                    from,
+                    SessionCommand::Accept {
+                        token,
+                        ssrc: self.ssrc,
+                    },
+                );
+            }
+            SessionCommand::Invitation { token, ssrc, .. } => match peers.sessions.get_mut(&ssrc) {
+                Some(peer) => {
+                    println!("Connected RTP-MIDI peer: '{}' ({})", peer.name, from.ip());
+                    peer.data_address = Some(from);
+                    peer.last_seen = Instant::now();
+                    self.reply(
+                        socket,
+                        from,
+                        SessionCommand::Accept {
+                            token,
+                            ssrc: self.ssrc,
+                        },
+                    );
+                }
+                // Invitations start at the control port.
+                None => self.reply(
+                    socket,
+                    from,
+                    SessionCommand::Reject {
+                        token,
+                        ssrc: self.ssrc,
+                    },
+                ),
+            },
+            SessionCommand::Bye { ssrc, .. } => {
+                if let Some(peer) = peers.sessions.remove(&ssrc) {
+                    println!("\nRTP-MIDI peer '{}' ended the session.", peer.name);
+                    peer.release_held(session);
+                }
+            }
+            SessionCommand::Sync {
+                ssrc,
+                count,
+                mut timestamps,
+            } => {
+                let Some(peer) = peers.sessions.get_mut(&ssrc) else {
+                    return;
+                };
+                peer.last_seen = Instant::now();
+                match count {
+                    0 => {
+                        timestamps[1] = self.clock();
+                        let reply = SessionCommand::Sync {
+                            ssrc: self.ssrc,
+                            count: 1,
+                            timestamps,
+                        };
+                        self.reply(socket, from, reply);
+                    }
+                    2 => {
+                        // The peer's clock read the middle of the round trip when ours read
+                        // the reply time.
+                        let remote = (timestamps[0] as i64 + timestamps[2] as i64) / 2;
+                        peer.clock_offset = Some(remote - timestamps[1] as i64);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reply(&self, socket: &UdpSocket, to: SocketAddr, command: SessionCommand) {
+        if let Err(e) = socket.send_to(&command.encode(), to) {
+            eprintln!("Cannot send RTP-MIDI reply: {}", e);
+        }
+    }
+
+    fn handle_rtp(&self, packet: &[u8], peers: &mut Peers, session: &SharedSession) {
+        let Some(packet) = RtpPacket::parse(packet) else {
+            return;
+        };
+        let Some(peer) = peers.sessions.get_mut(&packet.ssrc) else {
+            return;
+        };
+        let now = self.clock();
+        peer.last_seen = Instant::now();
+        if let Some(expected) = peer.next_sequence {
+            let ahead = packet.sequence.wrapping_sub(expected);
+            if ahead >= 0x8000 {
+                // Late or duplicate, its commands are covered by the journal already.
+                return;
+            }
+            if ahead > 0 {
+                println!("Lost {} RTP-MIDI packets from '{}'", ahead, peer.name);
+                if let Some(journal) = packet.journal {
+                    let timestamp = peer.local_time(packet.timestamp, now);
+                    for message in recovery_messages(journal, &peer.channels) {
+                        peer.record(self, session, &message, timestamp);
+                    }
+                }
+            }
+        }
+        peer.next_sequence = Some(packet.sequence.wrapping_add(1));
+
+        let mut time = packet.timestamp;
+        for (delta, message) in packet.commands(&mut peer.running_status) {
+            time = time.wrapping_add(delta);
+            let timestamp = peer.local_time(time, now);
+            match sysex_segment(&message) {
+                Some(Some(segment)) => peer.record(self, session, &segment, timestamp),
+                Some(None) => peer.sysex = SysExAssembler::default(),
+                None => peer.record(self, session, &message, timestamp),
+            }
+        }
+
+        if packet.journal.is_some()
+            && peer
+                .last_feedback
+                .is_none_or(|t| t.elapsed() >= FEEDBACK_INTERVAL)
+        {
+            peer.last_feedback = Some(Instant::now());
+            let feedback = SessionCommand::Feedback {
+                ssrc: self.ssrc,
+                sequence: packet.sequence,
+            };
+            self.reply(&self.control, peer.control_address, feedback);
+        }
+    }
+}
+
+/// A peer that has joined the session.
+struct Peer {
+    name: String,
+    token: u32,
+    control_address: SocketAddr,
+    /// Set once the peer has joined on the data port too.
+    data_address: Option<SocketAddr>,
+    track: usize,
+    /// Peer's clock minus ours, in units of 100 µs.
+    clock_offset: Option<i64>,
+    /// Sequence number of the next RTP packet.
+    next_sequence: Option<u16>,
+    running_status: Option<u8>,
+    last_timestamp: u64,
+    last_seen: Instant,
+    last_feedback: Option<Instant>,
+    sysex: SysExAssembler,
+    /// What the peer has played so far, compared with the journal after packet loss.
+    channels: [ChannelHistory; 16],
+}
+
+impl Peer {
+    fn new(name: String, token: u32, control_address: SocketAddr, track: usize) -> Self {
+        Peer {
+            name,
+            token,
+            control_address,
+            data_address: None,
+            track,
+            clock_offset: None,
+            next_sequence: None,
+            running_status: None,
+            last_timestamp: 0,
+            last_seen: Instant::now(),
+            last_feedback: None,
+            sysex: SysExAssembler::default(),
+            channels: [ChannelHistory::new(); 16],
+        }
+    }
+
+    /// Microseconds of the listener's clock at the RTP timestamp. Until the clocks are
+    /// synchronized, events are placed at the time they arrive.
+    fn local_time(&self, rtp_timestamp: u32, now: u64) -> u64 {
+        let Some(offset) = self.clock_offset else {
+            return now * 100;
+        };
+        // RTP timestamps are the low 32 bits of the peer's session clock.
+        let remote_now = now as i64 + offset;
+        let difference = rtp_timestamp.wrapping_sub(remote_now as u32) as i32 as i64;
+        (remote_now + difference - offset).max(0) as u64 * 100
+    }
+
+    fn record(
+        &mut self,
+        endpoint: &Endpoint,
+        session: &SharedSession,
+        message: &[u8],
+        timestamp: u64,
+    ) {
+        record_message(
+            session,
+            self.track,
+            &self.name,
+            message,
+            timestamp,
+            &mut self.sysex,
+            endpoint.record_timecode,
+        );
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        let status = message[0];
+        if status < 0xF0 {
+            self.channels[(status & 0x0F) as usize].remember(message);
+        }
+    }
+
+    fn release_held(&self, session: &SharedSession) {
+        session
+            .lock()
+            .unwrap()
+            .release_held(self.track, self.last_timestamp);
+    }
+}
+
+/// Channel state that the recovery journal describes.
+#[derive(Clone, Copy)]
+struct ChannelHistory {
+    /// Bit per note that is on.
+    notes: u128,
+    controllers: [Option<u8>; 128],
+    program: Option<u8>,
+}
+
+impl ChannelHistory {
+    fn new() -> Self {
+        ChannelHistory {
+            notes: 0,
+            controllers: [None; 128],
+            program: None,
+        }
+    }
+
+    fn remember(&mut self, message: &[u8]) {
+        let data = |i: usize| message.get(i).map(|b| b & 0x7F);
+        match (message[0] & 0xF0, data(1), data(2)) {
+            (0x90, Some(key), Some(velocity)) if velocity > 0 => self.notes |= 1 << key,
+            (0x80 | 0x90, Some(key), _) => self.notes &= !(1 << key),
+            (0xB0, Some(controller), Some(value)) => {
+                self.controllers[controller as usize] = Some(value)
+            }
+            (0xC0, Some(program), _) => self.program = Some(program),
+            _ => {}
+        }
+    }
+
+    fn is_on(&self, key: u8) -> bool {
+        self.notes & (1 << key) != 0
+    }
+}
+
+/// Session commands of the AppleMIDI protocol.
+#[derive(Debug, PartialEq)]
+enum SessionCommand {
+    Invitation {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Accept {
+        token: u32,
+        ssrc: u32,
+    },
+    Reject {
+        token: u32,
+        ssrc: u32,
+    },
+    Bye {
+        token: u32,
+        ssrc: u32,
+    },
+    /// Clock synchronization, timestamps are in units of 100 µs.
+    Sync {
+        ssrc: u32,
+        count: u8,
+        timestamps: [u64; 3],
+    },
+    /// Receiver feedback, the last RTP sequence number received.
+    Feedback {
+        ssrc: u32,
+        sequence: u16,
+    },
+}
+
+impl SessionCommand {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.get(..2)? != [0xFF, 0xFF] {
+            return None;
+        }
+        let word = |i: usize| Some(u32::from_be_bytes(packet.get(i..i + 4)?.try_into().ok()?));
+        let long = |i: usize| Some(u64::from_be_bytes(packet.get(i..i + 8)?.try_into().ok()?));
+        Some(match packet.get(2..4)? {
+            b"IN" => {
+                let name = packet.get(16..).unwrap_or_default();
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                SessionCommand::Invitation {
+                    token: word(8)?,
+                    ssrc: word(12)?,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                }
+            }
+            b"OK" => SessionCommand::Accept {
+                token: word(8)?,
+                ssrc: word(12)?,
+            },
+            b"NO" => SessionCommand::Reject {
+                token: word(8)?,
+                ssrc: word(12)?,
+            },
+            b"BY" => SessionCommand::Bye {
+                token: word(8)?,
+                ssrc: word(12)?,
+            },
+            b"CK" => SessionCommand::Sync {
+                ssrc: word(4)?,
+                count: *packet.get(8)?,
+                timestamps: [long(12)?, long(20)?, long(28)?],
+            },
+            b"RS" => SessionCommand::Feedback {
+                ssrc: word(4)?,
+                sequence: (word(8)? >> 16) as u16,
+            },
+            _ => return None,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = vec![0xFF, 0xFF];
+        let mut handshake = |command: &[u8], token: u32, ssrc: u32| {
+            packet.extend_from_slice(command);
+            packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            packet.extend_from_slice(&token.to_be_bytes());
+            packet.extend_from_slice(&ssrc.to_be_bytes());
+        };
+        match self {
+            SessionCommand::Invitation { token, ssrc, name } => {
+                handshake(b"IN", *token, *ssrc);
+                packet.extend_from_slice(name.as_bytes());
+                packet.push(0);
+            }
+            SessionCommand::Accept { token, ssrc } => handshake(b"OK", *token, *ssrc),
+            SessionCommand::Reject { token, ssrc } => handshake(b"NO", *token, *ssrc),
+            SessionCommand::Bye { token, ssrc } => handshake(b"BY", *token, *ssrc),
+            SessionCommand::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                packet.extend_from_slice(b"CK");
+                packet.extend_from_slice(&ssrc.to_be_bytes());
+                packet.extend_from_slice(&[*count, 0, 0, 0]);
+                for timestamp in timestamps {
+                    packet.extend_from_slice(&timestamp.to_be_bytes());
+                }
+            }
+            SessionCommand::Feedback { ssrc, sequence } => {
+                packet.extend_from_slice(b"RS");
+                packet.extend_from_slice(&ssrc.to_be_bytes());
+                packet.extend_from_slice(&((*sequence as u32) << 16).to_be_bytes());
+            }
+        }
+        packet
+    }
+}
+
+/// RTP packet with a MIDI payload.
+struct RtpPacket<'a> {
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+    /// Whether the first command has its status byte left out.
+    phantom_status: bool,
+    /// Whether the first command has a delta time.
+    first_delta: bool,
+    commands: &'a [u8],
+    journal: Option<&'a [u8]>,
+}
+
+impl<'a> RtpPacket<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < 13 || packet[0] >> 6 != 2 || packet[1] & 0x7F != PAYLOAD_TYPE {
+            return None;
+        }
+        let (sequence, timestamp, ssrc) = (
+            u16::from_be_bytes([packet[2], packet[3]]),
+            u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+            u32::from_be_bytes(packet[8..12].try_into().unwrap()),
+        );
+        // The fixed header is followed by contributing sources and an optional extension,
+        // padding is counted by the last byte.
+        let mut payload = 12 + 4 * (packet[0] as usize & 0x0F);
+        if packet[0] & 0x10 != 0 {
+            let words = packet.get(payload + 2..payload + 4)?;
+            payload += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+        }
+        let end = match packet[0] & 0x20 {
+            0 => packet.len(),
+            _ => packet.len().checked_sub(*packet.last()? as usize)?,
+        };
+        let packet = packet.get(payload..end)?;
+
+        let header = *packet.first()?;
+        let (length, start) = if header & 0x80 != 0 {
+            (
+                ((header as usize & 0x0F) << 8) | *packet.get(1)? as usize,
+                2,
+            )
+        } else {
+            (header as usize & 0x0F, 1)
+        };
+        let commands = packet.get(start..start + length)?;
+        let journal = (header & 0x40 != 0).then(|| &packet[start + length..]);
+        Some(RtpPacket {
+            sequence,
+            timestamp,
+            ssrc,
+            phantom_status: header & 0x10 != 0,
+            first_delta: header & 0x20 != 0,
+            commands,
+            journal,
+        })
+    }
+
+    /// MIDI commands with delta times from the previous command, in RTP timestamp units.
+    /// Running status carries over between packets.
+    fn commands(&self, running_status: &mut Option<u8>) -> Vec<(u32, Vec<u8>)> {
+        let list = self.commands;
+        let mut commands = Vec::new();
+        let mut i = 0;
+        if !self.phantom_status {
+            *running_status = None;
+        }
+        while i < list.len() {
+            let mut delta = 0;
+            if i > 0 || self.first_delta {
+                for _ in 0..4 {
+                    let Some(&byte) = list.get(i) else {
+                        return commands;
+                    };
+                    i += 1;
+                    delta = (delta << 7) | (byte & 0x7F) as u32;
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                }
+            }
+            let Some(&first) = list.get(i) else {
+                break;
+            };
+            let status = if first & 0x80 != 0 {
+                i += 1;
+                first
+            } else if let Some(status) = *running_status {
+                status
+            } else {
+                // Data without status, the rest cannot be decoded.
+                break;
+            };
+            let end = match status {
+                0xF0 | 0xF7 => match list[i..]
+                    .iter()
+                    .position(|&b| matches!(b, 0xF0 | 0xF7 | 0xF4))
+                {
+                    Some(position) => i + position + 1,
+                    None => break,
+                },
+                _ => i + data_length(status),
+            };
+            let Some(data) = list.get(i..end) else {
+                break;
+            };
+            i = end;
+            match status {
+                0x80..=0xEF => *running_status = Some(status),
+                0xF0..=0xF7 => *running_status = None,
+                _ => {}
+            }
+            commands.push((delta, [&[status], data].concat()));
+        }
+        commands
+    }
+}
+
+/// RTP-MIDI splits long SysEx into segments that end with 0xF0, and continue with 0xF7.
+/// Returns None for other messages, Some(None) if the peer cancelled the message, and
+/// otherwise the segment as a chunk for a [`SysExAssembler`].
+fn sysex_segment(message: &[u8]) -> Option<Option<Vec<u8>>> {
+    let (&first, &last) = (message.first()?, message.last()?);
+    if (first != 0xF0 && first != 0xF7) || message.len() < 2 {
+        return None;
+    }
+    let data = &message[1..message.len() - 1];
+    Some(match (first, last) {
+        (_, 0xF4) => None,
+        (0xF0, 0xF7) => Some(message.to_vec()),
+        (0xF0, _) => Some([&[0xF0], data].concat()),
+        (_, 0xF7) => Some([data, &[0xF7]].concat()),
+        _ => Some(data.to_vec()),
+    })
+}
+
+/// Messages that bring the channels to the state described by a recovery journal:
+/// missed note offs, notes, controller and program changes.
+fn recovery_messages(journal: &[u8], channels: &[ChannelHistory; 16]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let Some(&flags) = journal.first() else {
+        return messages;
+    };
+    let mut i = 3;
+    if flags & 0x40 != 0 {
+        // The system journal has nothing that affects recording.
+        let Some(header) = journal.get(i..i + 2) else {
+            return messages;
+        };
+        i += ((header[0] as usize & 0x03) << 8) | header[1] as usize;
+    }
+    if flags & 0x20 == 0 {
+        return messages;
+    }
+    for _ in 0..=(flags & 0x0F) {
+        let Some(header) = journal.get(i..i + 3) else {
+            break;
+        };
+        let channel = (header[0] >> 3) & 0x0F;
+        let length = ((header[0] as usize & 0x03) << 8) | header[1] as usize;
+        let Some(chapters) = journal.get(i + 3..i + length) else {
+            break;
+        };
+        let history = &channels[channel as usize];
+        recover_channel(channel, header[2], chapters, history, &mut messages);
+        i += length;
+    }
+    messages
+}
+
+/// Reads chapters P, C and N of a channel journal, skipping M and W between them.
+fn recover_channel(
+    channel: u8,
+    chapter_flags: u8,
+    chapters: &[u8],
+    history: &ChannelHistory,
+    messages: &mut Vec<Vec<u8>>,
+) -> Option<()> {
+    let mut i = 0;
+    if chapter_flags & 0x80 != 0 {
+        let program = *chapters.get(i)? & 0x7F;
+        if history.program != Some(program) {
+            messages.push(vec![0xC0 | channel, program]);
+        }
+        i += 3;
+    }
+    if chapter_flags & 0x40 != 0 {
+        let count = (*chapters.get(i)? & 0x7F) as usize + 1;
+        for log in chapters.get(i + 1..i + 1 + 2 * count)?.chunks(2) {
+            let (controller, value) = (log[0] & 0x7F, log[1]);
+            // Values with the A bit are toggle or count tools rather than values.
+            if value & 0x80 == 0 && history.controllers[controller as usize] != Some(value) {
+                messages.push(vec![0xB0 | channel, controller, value]);
+            }
+        }
+        i += 1 + 2 * count;
+    }
+    if chapter_flags & 0x20 != 0 {
+        let header = chapters.get(i..i + 2)?;
+        i += ((header[0] as usize & 0x03) << 8) | header[1] as usize;
+    }
+    if chapter_flags & 0x10 != 0 {
+        i += 2;
+    }
+    if chapter_flags & 0x08 != 0 {
+        let header = chapters.get(i..i + 2)?;
+        let (low, high) = (header[1] >> 4, header[1] & 0x0F);
+        let mut count = (header[0] & 0x7F) as usize;
+        let mut offbits = if low <= high {
+            (high - low + 1) as usize
+        } else {
+            0
+        };
+        if count == 127 && low == 15 && high == 0 {
+            count = 128;
+            offbits = 0;
+        }
+        i += 2;
+        let logs = chapters.get(i..i + 2 * count)?;
+        let offbits = chapters.get(i + 2 * count..i + 2 * count + offbits)?;
+        for (k, &bits) in offbits.iter().enumerate() {
+            for bit in 0..8 {
+                let key = (low as usize + k) * 8 + bit;
+                if bits & (0x80 >> bit) != 0 && history.is_on(key as u8) {
+                    messages.push(vec![0x80 | channel, key as u8, 0]);
+                }
+            }
+        }
+        for log in logs.chunks(2) {
+            let (key, velocity) = (log[0] & 0x7F, log[1] & 0x7F);
+            if velocity > 0 && !history.is_on(key) {
+                messages.push(vec![0x90 | channel, key, velocity]);
+            }
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_decoded_with_delta_times() {
+        // Note on, running status note on after 10 ticks, then a clock and a SysEx segment.
+        let list = [
+            0x90, 60, 100, 10, 62, 90, 0x81, 0x00, 0xF8, 0, 0xF0, 1, 2, 0xF0,
+        ];
+        let mut packet = vec![0x80, 0x61, 0, 1, 0, 0, 0, 100, 0, 0, 0, 7];
+        packet.extend_from_slice(&[0x80, list.len() as u8]);
+        packet.extend_from_slice(&list);
+        let packet = RtpPacket::parse(&packet).unwrap();
+        assert_eq!(
+            (packet.sequence, packet.timestamp, packet.ssrc),
+            (1, 100, 7)
+        );
+        let mut running_status = None;
+        assert_eq!(
+            packet.commands(&mut running_status),
+            vec![
+                (0, vec![0x90, 60, 100]),
+                (10, vec![0x90, 62, 90]),
+                (128, vec![0xF8]),
+                (0, vec![0xF0, 1, 2, 0xF0]),
+            ]
+        );
+        assert_eq!(
+            sysex_segment(&[0xF0, 1, 2, 0xF0]),
+            Some(Some(vec![0xF0, 1, 2]))
+        );
+        assert_eq!(sysex_segment(&[0xF7, 3, 0xF7]), Some(Some(vec![3, 0xF7])));
+        assert_eq!(sysex_segment(&[0xF7, 3, 0xF4]), Some(None));
+    }
+
+    #[test]
+    fn contributing_sources_extension_and_padding_are_skipped() {
+        // Two contributing sources, an extension of one word and two bytes of padding.
+        let mut packet = vec![0xB2, 0x61, 0, 1, 0, 0, 0, 100, 0, 0, 0, 7];
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[0xBE, 0xDE, 0, 1, 0x90, 0x90, 0x90, 0x90]);
+        packet.extend_from_slice(&[0x03, 0x90, 60, 100, 0, 2]);
+        let packet = RtpPacket::parse(&packet).unwrap();
+        assert_eq!(packet.ssrc, 7);
+        assert_eq!(packet.commands, [0x90, 60, 100]);
+        assert!(packet.journal.is_none());
+        // A contributing source that is cut short.
+        assert!(RtpPacket::parse(&[0x81, 0x61, 0, 1, 0, 0, 0, 100, 0, 0, 0, 7, 0x03]).is_none());
+    }
+
+    #[test]
+    fn journal_recovers_missed_messages() {
+        let mut history = ChannelHistory::new();
+        history.remember(&[0x92, 60, 100]);
+        history.remember(&[0x92, 64, 100]);
+        history.remember(&[0xB2, 64, 127]);
+        let mut channels = [ChannelHistory::new(); 16];
+        channels[2] = history;
+        // Channel 2 with chapters C and N: sustain released, note 64 off, note 67 on.
+        let chapters = [
+            0x00, 64, 0, // Chapter C: one log
+            0x01, 0x88, 67, 80, 0x80, // Chapter N: one log, offbits for notes 64..71
+        ];
+        let length = 3 + chapters.len() as u8;
+        let mut journal = vec![0x20, 0, 5, 2 << 3, length, 0x48];
+        journal.extend_from_slice(&chapters);
+        assert_eq!(
+            recovery_messages(&journal, &channels),
+            vec![vec![0xB2, 64, 0], vec![0x82, 64, 0], vec![0x92, 67, 80]]
+        );
+    }
+}
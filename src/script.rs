@@ -0,0 +1,181 @@
+use crate::recorder::{MidiSource, SharedSession};
+use crate::tempo::DEFAULT_USEC_PER_BEAT;
+use midly::live::LiveEvent;
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::Instant;
+
+/// A message that a [`ScriptedSource`] delivers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptedEvent {
+    /// Microseconds since the start of the script.
+    pub timestamp: u64,
+    /// Index into the track names of the script.
+    pub track: usize,
+    /// Complete MIDI message, SysEx included.
+    pub bytes: Vec<u8>,
+}
+
+/// Plays a prepared sequence of messages in the recorder's time,
+/// e.g. to test recording without MIDI hardware or to re-record a file.
+pub struct ScriptedSource {
+    track_names: Vec<String>,
+    /// Session track of each script track, once they are added.
+    tracks: Vec<usize>,
+    events: VecDeque<ScriptedEvent>,
+    start: Option<Instant>,
+}
+
+impl ScriptedSource {
+    /// Fails if an event refers to a track that has no name.
+    pub fn new(track_names: Vec<String>, events: Vec<ScriptedEvent>) -> Result<Self, String> {
+        if let Some(event) = events.iter().find(|e| e.track >= track_names.len()) {
+            return Err(format!(
+                "Scripted event at {} us is on track {}, but there are only {} tracks",
+                event.timestamp,
+                event.track,
+                track_names.len()
+            ));
+        }
+        Ok(Self::with_events(track_names, events))
+    }
+
+    fn with_events(track_names: Vec<String>, mut events: Vec<ScriptedEvent>) -> Self {
+        events.sort_by_key(|e| e.timestamp);
+        ScriptedSource {
+            track_names,
+            tracks: Vec::new(),
+            events: events.into(),
+            start: None,
+        }
+    }
+
+    /// Replays a Standard MIDI File, one input per track that has events.
+    pub fn from_smf(data: &[u8]) -> Result<Self, midly::Error> {
+        let smf = Smf::parse(data)?;
+        let tempo_map = TempoMap::new(&smf);
+        let mut track_names = Vec::new();
+        let mut events = Vec::new();
+        for (i, track) in smf.tracks.iter().enumerate() {
+            let mut name = None;
+            let mut track_events = Vec::new();
+            let mut tick = 0;
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                let bytes = match event.kind {
+                    TrackEventKind::Meta(MetaMessage::TrackName(text)) => {
+                        name.get_or_insert_with(|| String::from_utf8_lossy(text).into_owned());
+                        continue;
+                    }
+                    TrackEventKind::Meta(_) => continue,
+                    TrackEventKind::SysEx(data) => [&[0xF0], data].concat(),
+                    TrackEventKind::Escape(data) => data.to_vec(),
+                    TrackEventKind::Midi { channel, message } => {
+                        let event = LiveEvent::Midi { channel, message };
+                        let mut bytes = Vec::new();
+                        if event.write_std(&mut bytes).is_err() {
+                            continue;
+                        }
+                        bytes
+                    }
+                };
+                track_events.push(ScriptedEvent {
+                    timestamp: tempo_map.microseconds(tick),
+                    track: track_names.len(),
+                    bytes,
+                });
+            }
+            if !track_events.is_empty() {
+                track_names.push(name.unwrap_or_else(|| format!("Track {}", i + 1)));
+                events.extend(track_events);
+            }
+        }
+        // Events refer to the tracks that are named above.
+        Ok(Self::with_events(track_names, events))
+    }
+
+    /// Whether all events are delivered.
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl MidiSource for ScriptedSource {
+    /// Delivers the events that are due, with their scripted timestamps.
+    fn poll(&mut self, session: &SharedSession, now: Instant) -> Result<(), Box<dyn Error>> {
+        let mut session = session.lock().unwrap();
+        if self.tracks.len() < self.track_names.len() {
+            for name in &self.track_names[self.tracks.len()..] {
+                self.tracks.push(session.add_track(name.clone()));
+            }
+        }
+        let elapsed = now.duration_since(*self.start.get_or_insert(now));
+        while let Some(event) = self.events.front() {
+            if event.timestamp > elapsed.as_micros() as u64 {
+                break;
+            }
+            let event = self.events.pop_front().unwrap();
+            if let Ok(live_event) = LiveEvent::parse(&event.bytes) {
+                session.add_event(self.tracks[event.track], live_event, event.timestamp);
+            }
+        }
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    fn input_count(&self) -> usize {
+        self.track_names.len()
+    }
+}
+
+/// Converts ticks of a file into microseconds.
+struct TempoMap {
+    /// Tick, microseconds at the tick and microseconds per tick from then on.
+    segments: Vec<(u64, f64, f64)>,
+}
+
+impl TempoMap {
+    fn new(smf: &Smf) -> Self {
+        let ticks_per_beat = match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int() as f64,
+            Timing::Timecode(fps, subframes) => {
+                let usec_per_tick = 1_000_000.0 / (fps.as_f32() as f64 * subframes as f64);
+                return TempoMap {
+                    segments: vec![(0, 0.0, usec_per_tick)],
+                };
+            }
+        };
+        // Tempo changes apply to all tracks.
+        let mut changes = Vec::new();
+        for track in &smf.tracks {
+            let mut tick = 0;
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                    changes.push((tick, tempo.as_int()));
+                }
+            }
+        }
+        changes.sort_by_key(|c| c.0);
+        let mut segments = vec![(0, 0.0, DEFAULT_USEC_PER_BEAT as f64 / ticks_per_beat)];
+        for (tick, usec_per_beat) in changes {
+            let (last_tick, last_usec, usec_per_tick) = *segments.last().unwrap();
+            segments.push((
+                tick,
+                last_usec + (tick - last_tick) as f64 * usec_per_tick,
+                usec_per_beat as f64 / ticks_per_beat,
+            ));
+        }
+        TempoMap { segments }
+    }
+
+    fn microseconds(&self, tick: u64) -> u64 {
+        let segment = self.segments.partition_point(|s| s.0 <= tick) - 1;
+        let (start_tick, start_usec, usec_per_tick) = self.segments[segment];
+        (start_usec + (tick - start_tick) as f64 * usec_per_tick).round() as u64
+    }
+}
@@ -0,0 +1,1081 @@
+use crate::clock::{Clock, SystemClock};
+use crate::controls::{ControlAction, Controls};
+use crate::journal::Journal;
+use crate::state::InstrumentState;
+use crate::tempo::{ClockState, TickMap};
+use chrono::{DateTime, Local};
+use midly::live::{LiveEvent, SystemCommon, SystemRealtime};
+use midly::num::{u24, u28, u4, u7};
+use midly::{Format, Header, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind};
+use std::borrow::Cow;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+const DEFAULT_USEC_PER_TICK: u32 = 500; // 120 BPM with 1000 ticks per beat
+const DEFAULT_TICKS_PER_BEAT: u16 = 1000;
+/// Ends names of files with takes marked as keepers.
+pub const KEEPER_SUFFIX: &str = "-keep";
+/// Time after a transport Stop that notes still held are waited for.
+const TRANSPORT_STOP_SETTLE: Duration = Duration::from_secs(1);
+/// Number of pauses after which a take ends even though notes or pedals are held,
+/// in case a Note Off was lost or a pedal reports the opposite of its position.
+const HELD_PAUSE_LIMIT: u32 = 8;
+/// Longest pause that fits into a single SMF event delta.
+const MAX_DELTA_TICKS: u64 = (1 << 28) - 1;
+
+/// Owned counterpart of `TrackEventKind`, so events with payload can outlive the MIDI callback.
+#[derive(Clone, Debug, PartialEq)]
+enum RecordedKind {
+    Midi {
+        channel: u4,
+        message: MidiMessage,
+    },
+    /// Bytes following the leading 0xF0, including the terminating 0xF7.
+    SysEx(Vec<u8>),
+    /// Raw message bytes (status included) stored as an SMF escape sequence.
+    Escape(Vec<u8>),
+    /// Transport messages.
+    Marker(&'static str),
+    CuePoint(String),
+}
+
+impl RecordedKind {
+    fn as_track_event_kind(&self) -> TrackEventKind<'_> {
+        match self {
+            RecordedKind::Midi { channel, message } => TrackEventKind::Midi {
+                channel: *channel,
+                message: *message,
+            },
+            RecordedKind::SysEx(data) => TrackEventKind::SysEx(data),
+            RecordedKind::Escape(data) => TrackEventKind::Escape(data),
+            RecordedKind::Marker(text) => {
+                TrackEventKind::Meta(midly::MetaMessage::Marker(text.as_bytes()))
+            }
+            RecordedKind::CuePoint(text) => {
+                TrackEventKind::Meta(midly::MetaMessage::CuePoint(text.as_bytes()))
+            }
+        }
+    }
+}
+
+struct RecordedEvent {
+    /// Absolute time since the start of the session.
+    tick: u64,
+    kind: RecordedKind,
+}
+
+/// Longer System Exclusive messages are dropped rather than buffered, e.g. a sample dump
+/// or a sender that never ends the message.
+pub(crate) const MAX_SYSEX_LENGTH: usize = 256 * 1024;
+
+/// Collects System Exclusive messages that a MIDI backend may deliver in several chunks.
+#[derive(Default)]
+pub struct SysExAssembler {
+    buffer: Vec<u8>,
+    /// The rest of a message that was too long is skipped.
+    skipping: bool,
+}
+
+impl SysExAssembler {
+    /// Returns complete message bytes, or None while a SysEx message is still incomplete.
+    pub fn push<'a>(&mut self, message: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let status = *message.first()?;
+        if status >= 0xF8 {
+            // Realtime messages may be interleaved with SysEx chunks.
+            return Some(Cow::Borrowed(message));
+        }
+        if self.skipping {
+            if status < 0x80 || status == 0xF7 {
+                self.skipping = message.last() != Some(&0xF7);
+                return None;
+            }
+            self.skipping = false;
+        }
+        if !self.buffer.is_empty() {
+            if status < 0x80 || status == 0xF7 {
+                if self.buffer.len() + message.len() > MAX_SYSEX_LENGTH {
+                    eprintln!(
+                        "Dropping SysEx message longer than {} bytes.",
+                        MAX_SYSEX_LENGTH
+                    );
+                    self.buffer = Vec::new();
+                    self.skipping = message.last() != Some(&0xF7);
+                    return None;
+                }
+                self.buffer.extend_from_slice(message);
+                return if message.last() == Some(&0xF7) {
+                    Some(Cow::Owned(std::mem::take(&mut self.buffer)))
+                } else {
+                    None
+                };
+            }
+            eprintln!(
+                "Dropping incomplete SysEx message of {} bytes.",
+                self.buffer.len()
+            );
+            self.buffer.clear();
+        }
+        if status == 0xF0 && message.last() != Some(&0xF7) {
+            self.buffer.extend_from_slice(message);
+            return None;
+        }
+        Some(Cow::Borrowed(message))
+    }
+}
+
+/// Number of data bytes after the status byte, for messages other than SysEx.
+pub(crate) fn data_length(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+/// Decides when the current take is written to a file.
+#[derive(Clone, Debug)]
+pub struct SplitPolicy {
+    /// Silence that ends a take.
+    pub pause: Duration,
+    /// Takes longer than this are split even while playing.
+    pub max_length: Option<Duration>,
+    /// Do not end a take on silence while notes or pedals are held.
+    pub wait_for_release: bool,
+    /// Takes with fewer events are discarded.
+    pub min_events: usize,
+    /// Start a take on transport Start or Continue and end it on Stop. While the transport
+    /// plays, takes are not split on silence.
+    pub follow_transport: bool,
+}
+
+impl Default for SplitPolicy {
+    fn default() -> Self {
+        SplitPolicy {
+            pause: Duration::from_secs(8),
+            max_length: None,
+            wait_for_release: true,
+            min_events: 1,
+            follow_transport: false,
+        }
+    }
+}
+
+/// Events and instrument state of a single input port.
+struct PortTrack {
+    name: String,
+    /// Absolute tick of the last event.
+    /// Deltas are derived from it so rounding errors do not add up.
+    last_tick: u64,
+    events: Vec<RecordedEvent>,
+    /// Carried over between takes: the instrument keeps its settings
+    /// and notes can be held across a split.
+    state: InstrumentState,
+    /// State when the current take started, it is restored at the beginning of the file.
+    take_start: InstrumentState,
+}
+
+impl PortTrack {
+    fn new(name: String) -> Self {
+        PortTrack {
+            name,
+            last_tick: 0,
+            events: Vec::new(),
+            state: InstrumentState::default(),
+            take_start: InstrumentState::default(),
+        }
+    }
+
+    fn add_event(&mut self, tick: u64, kind: RecordedKind) {
+        let tick = tick.max(self.last_tick);
+        self.last_tick = tick;
+        if let RecordedKind::Midi { channel, message } = kind {
+            self.state.update(channel, message);
+        }
+        self.events.push(RecordedEvent { tick, kind });
+    }
+
+    /// Track of the current take, anything still held is released at `end_tick`.
+    /// Tempo changes are given as tick and microseconds per beat.
+    fn track(&self, end_tick: u64, tempo_changes: &[(u64, u32)]) -> Track<'_> {
+        let mut track = Track::new();
+        track.push(TrackEvent {
+            delta: u28::from(0),
+            kind: TrackEventKind::Meta(midly::MetaMessage::TrackName(self.name.as_bytes())),
+        });
+        let mut tempo_changes = tempo_changes.iter().filter(|c| c.0 <= end_tick).peekable();
+        while let Some((_, usec_per_beat)) = tempo_changes.next_if(|c| c.0 == 0) {
+            Self::push_track_event(&mut track, 0, Self::tempo_event(*usec_per_beat));
+        }
+        for (channel, message) in self.take_start.restore_messages() {
+            track.push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+        }
+        let mut last_tick = 0;
+        for event in &self.events {
+            while let Some((tick, usec_per_beat)) = tempo_changes.next_if(|c| c.0 <= event.tick) {
+                Self::push_track_event(
+                    &mut track,
+                    tick - last_tick,
+                    Self::tempo_event(*usec_per_beat),
+                );
+                last_tick = *tick;
+            }
+            Self::push_track_event(
+                &mut track,
+                event.tick - last_tick,
+                event.kind.as_track_event_kind(),
+            );
+            last_tick = event.tick;
+        }
+        for (tick, usec_per_beat) in tempo_changes {
+            Self::push_track_event(
+                &mut track,
+                tick - last_tick,
+                Self::tempo_event(*usec_per_beat),
+            );
+            last_tick = *tick;
+        }
+        // Release anything still held so the file does not end with hanging notes.
+        for (i, (channel, message)) in self.state.held.release_messages().into_iter().enumerate() {
+            let delta = if i == 0 { end_tick - last_tick } else { 0 };
+            Self::push_track_event(&mut track, delta, TrackEventKind::Midi { channel, message });
+        }
+        track.push(TrackEvent {
+            delta: u28::from(0),
+            kind: TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        });
+        track
+    }
+
+    fn tempo_event<'a>(usec_per_beat: u32) -> TrackEventKind<'a> {
+        TrackEventKind::Meta(midly::MetaMessage::Tempo(u24::from(usec_per_beat)))
+    }
+
+    /// Pauses longer than a delta can hold are bridged with empty text events.
+    fn push_track_event<'a>(track: &mut Track<'a>, mut delta: u64, kind: TrackEventKind<'a>) {
+        while delta > MAX_DELTA_TICKS {
+            track.push(TrackEvent {
+                delta: u28::max_value(),
+                kind: TrackEventKind::Meta(midly::MetaMessage::Text(b"")),
+            });
+            delta -= MAX_DELTA_TICKS;
+        }
+        track.push(TrackEvent {
+            delta: u28::from(delta as u32),
+            kind,
+        });
+    }
+
+    fn reset(&mut self) {
+        self.last_tick = 0;
+        self.events.clear();
+        self.take_start = self.state.clone();
+    }
+}
+
+/// Events of the take in progress, one track per input.
+pub struct RecordingSession {
+    policy: SplitPolicy,
+    /// Event timestamps in microseconds, as reported by the MIDI backend.
+    first_timestamp: Option<u64>,
+    last_timestamp: Option<u64>,
+    /// Wall clock time of the first and the last event, used to detect pauses and long takes.
+    first_event_time: Option<Instant>,
+    last_event_time: Option<Instant>,
+    /// Ticks of the take, following MIDI clock if there is one.
+    tempo: TickMap,
+    /// One per input port.
+    tracks: Vec<PortTrack>,
+    /// Where journals of takes are kept, no journal is written if not set.
+    journal_directory: Option<PathBuf>,
+    journal: Option<Journal>,
+    clock: Arc<dyn Clock>,
+    /// Between transport Start or Continue and Stop.
+    transport_playing: bool,
+    /// The transport stopped during the take.
+    transport_stopped: bool,
+    /// Port, message bytes and timestamp of events after a transport start that ended the take.
+    /// They are recorded once the take is saved.
+    next_take: Option<Vec<(usize, Vec<u8>, u64)>>,
+    /// MIDI control bindings, applied before events are recorded.
+    controls: Option<Controls>,
+    /// A control asked to save the take now.
+    split_requested: bool,
+    /// A control marked the take as a keeper.
+    keeper: bool,
+    /// Control actions for the last saved take, they are carried out by the recorder.
+    control_requests: Vec<ControlAction>,
+}
+
+impl RecordingSession {
+    pub fn new(policy: SplitPolicy, port_names: Vec<String>) -> Self {
+        RecordingSession {
+            policy,
+            first_timestamp: None,
+            last_timestamp: None,
+            first_event_time: None,
+            last_event_time: None,
+            tempo: TickMap::new(DEFAULT_TICKS_PER_BEAT),
+            tracks: port_names.into_iter().map(PortTrack::new).collect(),
+            journal_directory: None,
+            journal: None,
+            clock: Arc::new(SystemClock),
+            transport_playing: false,
+            transport_stopped: false,
+            next_take: None,
+            controls: None,
+            split_requested: false,
+            keeper: false,
+            control_requests: Vec::new(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Journals takes in the directory, so they can be recovered after a crash.
+    pub fn with_journal(mut self, directory: PathBuf) -> Self {
+        self.journal_directory = Some(directory);
+        self
+    }
+
+    /// Handles MIDI control bindings before events are recorded.
+    pub fn with_controls(mut self, controls: Controls) -> Self {
+        self.controls = Some(controls);
+        self
+    }
+
+    /// Records the event into the track of the port, `timestamp` is in microseconds.
+    /// Events bound to controls trigger their actions and may be left out.
+    pub fn add_event(&mut self, port: usize, event: LiveEvent, timestamp: u64) {
+        let Some(controls) = &mut self.controls else {
+            self.record_event(port, event, timestamp);
+            return;
+        };
+        let handled = controls.handle(port, &event, timestamp, self.first_timestamp.is_none());
+        for (event, timestamp) in handled.released {
+            self.record_event(port, event, timestamp);
+        }
+        // Controls apply to the take in progress, they do not start one. Neither do releases
+        // of controls after the take was saved or discarded.
+        let take_in_progress = self.first_timestamp.is_some();
+        if handled.record && (take_in_progress || !handled.control) {
+            self.record_event(port, event, timestamp);
+        }
+        let Some(action) = handled.action else {
+            return;
+        };
+        if !take_in_progress {
+            if action == ControlAction::Split {
+                return;
+            }
+            println!("\nControl: {:?} the last saved take.", action);
+            self.control_requests.push(action);
+            return;
+        }
+        println!("\nControl: {:?} the take.", action);
+        match action {
+            ControlAction::Split => self.split_requested = true,
+            ControlAction::Discard => {
+                self.remove_journal();
+                self.reset();
+            }
+            ControlAction::Keep => self.mark_keeper(port, timestamp),
+        }
+    }
+
+    /// Marks the take in progress as a keeper, with a marker on the track of the port.
+    pub(crate) fn mark_keeper(&mut self, port: usize, timestamp: u64) {
+        self.keeper = true;
+        let tick = self.tempo.tick(timestamp);
+        self.tracks[port].add_event(tick, RecordedKind::Marker("Keep"));
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.keep(port, timestamp) {
+                eprintln!("Cannot write journal: {}", e);
+                self.journal = None;
+            }
+        }
+    }
+
+    /// Discard or Keep actions for the last saved take since the previous call,
+    /// a split only applies to the take in progress.
+    pub fn take_control_requests(&mut self) -> Vec<ControlAction> {
+        std::mem::take(&mut self.control_requests)
+    }
+
+    /// Records the event into the track of the port. MIDI clock is not recorded,
+    /// it sets the tempo of the take.
+    fn record_event(&mut self, port: usize, event: LiveEvent, timestamp: u64) {
+        if let Some(next_take) = &mut self.next_take {
+            let mut bytes = Vec::new();
+            if event.write_std(&mut bytes).is_ok() {
+                next_take.push((port, bytes, timestamp));
+            }
+            return;
+        }
+        match event {
+            LiveEvent::Realtime(SystemRealtime::TimingClock) => {
+                self.tempo.pulse(port, timestamp);
+                // Recovery follows the clock from the take start the way recording did.
+                if self.first_timestamp.is_some() {
+                    self.journal_event(port, event, timestamp);
+                }
+                return;
+            }
+            LiveEvent::Realtime(SystemRealtime::Start | SystemRealtime::Continue) => {
+                if self.policy.follow_transport && self.first_timestamp.is_some() {
+                    // The take ends here, the next one starts with this event.
+                    self.next_take = Some(Vec::new());
+                    self.record_event(port, event, timestamp);
+                    return;
+                }
+                self.transport_playing = true;
+                self.transport_stopped = false;
+                if event == LiveEvent::Realtime(SystemRealtime::Start) {
+                    self.tempo.set_song_position(timestamp, 0);
+                }
+            }
+            LiveEvent::Realtime(SystemRealtime::Stop) => {
+                self.transport_playing = false;
+                if self.policy.follow_transport && self.first_timestamp.is_none() {
+                    return;
+                }
+                self.transport_stopped = true;
+            }
+            LiveEvent::Common(SystemCommon::SongPosition(position)) => {
+                self.tempo
+                    .set_song_position(timestamp, position.as_int() as u64);
+            }
+            _ => {}
+        }
+        let Some(kind) = Self::live_event_to_recorded_kind(event) else {
+            return;
+        };
+        if self.first_timestamp.is_none() {
+            self.open_journal(timestamp);
+        }
+        self.journal_event(port, event, timestamp);
+        self.first_timestamp.get_or_insert(timestamp);
+        let tick = self.tempo.tick(timestamp);
+        self.last_timestamp = self.last_timestamp.max(Some(timestamp));
+        let now = self.clock.now();
+        self.first_event_time.get_or_insert(now);
+        self.last_event_time = Some(now);
+        self.tracks[port].add_event(tick, kind);
+    }
+
+    /// Adds a track for another input, returns its index.
+    pub fn add_track(&mut self, name: String) -> usize {
+        let track = self.tracks.len();
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.track(track, &name) {
+                eprintln!("Cannot write journal: {}", e);
+                self.journal = None;
+            }
+        }
+        self.tracks.push(PortTrack::new(name));
+        track
+    }
+
+    pub fn set_track_name(&mut self, track: usize, name: String) {
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.track(track, &name) {
+                eprintln!("Cannot write journal: {}", e);
+                self.journal = None;
+            }
+        }
+        self.tracks[track].name = name;
+    }
+
+    /// Follows the MIDI clock from the given state, for takes recovered from a journal.
+    pub(crate) fn resume_clock(&mut self, clock: ClockState) {
+        self.tempo.resume_clock(clock);
+    }
+
+    pub fn policy(&self) -> &SplitPolicy {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: SplitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Starts a journal for a new take. It begins with the state restored at the start of the take.
+    fn open_journal(&mut self, timestamp: u64) {
+        let Some(directory) = &self.journal_directory else {
+            return;
+        };
+        let result = Journal::create(directory, self.clock.wall_time()).and_then(|mut journal| {
+            for (i, track) in self.tracks.iter().enumerate() {
+                journal.track(i, &track.name)?;
+            }
+            if let Some(clock) = self.tempo.clock() {
+                journal.clock(clock)?;
+            }
+            for (i, track) in self.tracks.iter().enumerate() {
+                for (channel, message) in track.take_start.restore_messages() {
+                    let mut bytes = Vec::new();
+                    LiveEvent::Midi { channel, message }.write_std(&mut bytes)?;
+                    journal.event(i, timestamp, &bytes)?;
+                }
+            }
+            Ok(journal)
+        });
+        match result {
+            Ok(journal) => self.journal = Some(journal),
+            Err(e) => eprintln!("Cannot create journal in {}: {}", directory.display(), e),
+        }
+    }
+
+    fn journal_event(&mut self, port: usize, event: LiveEvent, timestamp: u64) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let mut bytes = Vec::new();
+        let result = event
+            .write_std(&mut bytes)
+            .and_then(|_| journal.event(port, timestamp, &bytes));
+        if let Err(e) = result {
+            eprintln!("Cannot write journal: {}", e);
+            self.journal = None;
+        }
+    }
+
+    /// Flushes the journal to the storage device.
+    pub fn sync_journal(&mut self) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.sync() {
+                eprintln!("Cannot write journal: {}", e);
+                self.journal = None;
+            }
+        }
+    }
+
+    fn remove_journal(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            if let Err(e) = journal.remove() {
+                eprintln!("Cannot remove journal: {}", e);
+            }
+        }
+    }
+
+    /// Releases notes and pedals of an input that is no longer available.
+    pub fn release_held(&mut self, port: usize, timestamp: u64) {
+        for (channel, message) in self.tracks[port].state.held.release_messages() {
+            self.record_event(port, LiveEvent::Midi { channel, message }, timestamp);
+        }
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.tracks.iter().map(|t| t.events.len()).sum()
+    }
+
+    /// Whether the current take should be written out now, according to the split policy.
+    pub fn split_due(&self, now: Instant) -> bool {
+        let (Some(first), Some(last)) = (self.first_event_time, self.last_event_time) else {
+            return false;
+        };
+        if self.split_requested {
+            return true;
+        }
+        if self.policy.follow_transport {
+            if self.next_take.is_some() {
+                return true;
+            }
+            // Sequencers release their notes right after Stop.
+            if self.transport_stopped
+                && (now.duration_since(last) >= TRANSPORT_STOP_SETTLE
+                    || self.tracks.iter().all(|t| t.state.held.is_released()))
+            {
+                return true;
+            }
+        }
+        if let Some(max_length) = self.policy.max_length {
+            if now.duration_since(first) >= max_length {
+                return true;
+            }
+        }
+        if self.policy.follow_transport && self.transport_playing {
+            return false;
+        }
+        let silence = now.duration_since(last);
+        silence > self.policy.pause
+            && (!self.policy.wait_for_release
+                || silence > self.policy.pause * HELD_PAUSE_LIMIT
+                || self.tracks.iter().all(|t| t.state.held.is_released()))
+    }
+
+    fn live_event_to_recorded_kind(event: LiveEvent) -> Option<RecordedKind> {
+        match event {
+            LiveEvent::Midi { channel, message } => Some(RecordedKind::Midi { channel, message }),
+            LiveEvent::Common(SystemCommon::SysEx(data)) => {
+                let mut bytes = Vec::with_capacity(data.len() + 1);
+                bytes.extend_from_slice(u7::slice_as_int(data));
+                bytes.push(0xF7);
+                Some(RecordedKind::SysEx(bytes))
+            }
+            LiveEvent::Common(SystemCommon::SongPosition(position)) => Some(
+                RecordedKind::CuePoint(format!("Song Position {}", position.as_int())),
+            ),
+            LiveEvent::Common(common) => {
+                let mut bytes = Vec::new();
+                LiveEvent::Common(common).write_std(&mut bytes).ok()?;
+                Some(RecordedKind::Escape(bytes))
+            }
+            LiveEvent::Realtime(SystemRealtime::Start) => Some(RecordedKind::Marker("Start")),
+            LiveEvent::Realtime(SystemRealtime::Continue) => Some(RecordedKind::Marker("Continue")),
+            LiveEvent::Realtime(SystemRealtime::Stop) => Some(RecordedKind::Marker("Stop")),
+            LiveEvent::Realtime(_) => None, // Skip other realtime events
+        }
+    }
+
+    /// Encodes the current take as a Standard MIDI File named after `file_time` and hands it
+    /// to `write`. Once written, the take is finished and a new one starts.
+    /// Takes with fewer events than the policy requires are discarded without writing.
+    pub fn save_take(
+        &mut self,
+        file_time: DateTime<Local>,
+        write: impl FnOnce(&str, &[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.first_timestamp.is_none() {
+            assert_eq!(self.event_count(), 0);
+            println!("\nNo events, skipping save.");
+            return Ok(());
+        }
+        assert!(self.event_count() > 0 && self.last_timestamp.is_some());
+        if self.event_count() < self.policy.min_events && !self.keeper {
+            println!(
+                "\nDiscarding take with {} events (less than {}).",
+                self.event_count(),
+                self.policy.min_events
+            );
+            self.remove_journal();
+            self.reset();
+            return Ok(());
+        }
+        let tracks = self.tracks();
+        let event_count: usize = tracks.iter().map(|t| t.len()).sum();
+        let file_name = format!(
+            "{}-{}e-{}s{}.mid",
+            file_time.format("%Y-%m-%d_%H:%M:%S"),
+            event_count,
+            Duration::from_micros(self.last_timestamp.unwrap() - self.first_timestamp.unwrap())
+                .as_secs_f64()
+                .ceil() as i64,
+            if self.keeper { KEEPER_SUFFIX } else { "" }
+        );
+
+        let timing = Timing::Metrical(midly::num::u15::from(DEFAULT_TICKS_PER_BEAT));
+        let format = if tracks.len() > 1 {
+            Format::Parallel
+        } else {
+            Format::SingleTrack
+        };
+        let mut smf = Smf::new(Header::new(format, timing));
+        smf.tracks = tracks;
+
+        let mut output = Vec::new();
+        smf.write(&mut output)
+            .map_err(|e| io::Error::other(format!("MIDI write error: {:?}", e)))?;
+
+        write(&file_name, &output)?;
+        println!("Wrote {} events.", event_count);
+        self.remove_journal();
+        self.reset();
+
+        Ok(())
+    }
+
+    /// SMF tracks of the current take.
+    pub fn tracks(&self) -> Vec<Track<'_>> {
+        let end_tick = self.tracks.iter().map(|t| t.last_tick).max().unwrap_or(0);
+        // Tempo changes go into the first track.
+        let tempo_changes = self.tempo.tempo_changes();
+        self.tracks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| t.track(end_tick, if i == 0 { tempo_changes } else { &[] }))
+            .collect()
+    }
+
+    /// Drops the current take, keeping the instrument state.
+    pub fn reset(&mut self) {
+        self.first_timestamp = None;
+        self.last_timestamp = None;
+        self.first_event_time = None;
+        self.last_event_time = None;
+        self.transport_stopped = false;
+        self.split_requested = false;
+        self.keeper = false;
+        self.tempo.reset();
+        for track in &mut self.tracks {
+            track.reset();
+        }
+        // The take that ended at a transport start is over, the next one begins.
+        for (port, bytes, timestamp) in self.next_take.take().into_iter().flatten() {
+            if let Ok(event) = LiveEvent::parse(&bytes) {
+                self.record_event(port, event, timestamp);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::Arena;
+
+    fn new_session() -> RecordingSession {
+        RecordingSession::new(SplitPolicy::default(), vec!["Test port".to_string()])
+    }
+
+    fn note_on(key: u8) -> LiveEvent<'static> {
+        LiveEvent::Midi {
+            channel: u4::from(0),
+            message: MidiMessage::NoteOn {
+                key: u7::from(key),
+                vel: u7::from(64),
+            },
+        }
+    }
+
+    fn note_off(key: u8) -> LiveEvent<'static> {
+        LiveEvent::Midi {
+            channel: u4::from(0),
+            message: MidiMessage::NoteOff {
+                key: u7::from(key),
+                vel: u7::from(0),
+            },
+        }
+    }
+
+    fn absolute_ticks(session: &RecordingSession) -> Vec<u64> {
+        session.tracks[0].events.iter().map(|e| e.tick).collect()
+    }
+
+    #[test]
+    fn sysex_chunks_are_reassembled() {
+        let mut sysex = SysExAssembler::default();
+        assert_eq!(sysex.push(&[0xF0, 0x41, 0x10]), None);
+        // Realtime messages may come between the chunks.
+        assert_eq!(sysex.push(&[0xF8]).unwrap().as_ref(), [0xF8]);
+        assert_eq!(sysex.push(&[0x42, 0x12]), None);
+        assert_eq!(
+            sysex.push(&[0x40, 0xF7]).unwrap().as_ref(),
+            [0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0xF7]
+        );
+        // A message that is cut short by another one is dropped.
+        assert_eq!(sysex.push(&[0xF0, 0x7E]), None);
+        assert_eq!(
+            sysex.push(&[0x90, 60, 100]).unwrap().as_ref(),
+            [0x90, 60, 100]
+        );
+        assert_eq!(sysex.push(&[0x01, 0xF7]).unwrap().as_ref(), [0x01, 0xF7]);
+    }
+
+    #[test]
+    fn sysex_and_common_messages_are_framed_in_file() {
+        let mut session = new_session();
+        session.add_event(0, note_on(60), 0);
+        let mut sysex = SysExAssembler::default();
+        sysex.push(&[0xF0, 0x41, 0x10]);
+        let message = sysex.push(&[0x42, 0xF7]).unwrap();
+        session.add_event(0, LiveEvent::parse(&message).unwrap(), 1_000);
+        session.add_event(0, LiveEvent::parse(&[0xF6]).unwrap(), 2_000);
+        session.add_event(0, note_off(60), 3_000);
+        let mut data = Vec::new();
+        session
+            .save_take(Local::now(), |_, bytes| {
+                data = bytes.to_vec();
+                Ok(())
+            })
+            .unwrap();
+        // The SMF event holds the length and the bytes after F0, F7 included.
+        let framed = [0xF0, 0x04, 0x41, 0x10, 0x42, 0xF7];
+        assert!(data.windows(framed.len()).any(|w| w == framed));
+        let smf = Smf::parse(&data).unwrap();
+        let kinds: Vec<_> = smf.tracks[0].iter().map(|e| e.kind).collect();
+        assert!(kinds.contains(&TrackEventKind::SysEx(&[0x41, 0x10, 0x42, 0xF7])));
+        assert!(kinds.contains(&TrackEventKind::Escape(&[0xF6])));
+    }
+
+    #[test]
+    fn long_sysex_is_dropped() {
+        let mut sysex = SysExAssembler::default();
+        let chunk = [1; 1024];
+        assert_eq!(sysex.push(&[0xF0, 1]), None);
+        for _ in 0..MAX_SYSEX_LENGTH / chunk.len() {
+            assert_eq!(sysex.push(&chunk), None);
+        }
+        assert_eq!(sysex.push(&[1, 0xF7]), None);
+        assert_eq!(
+            sysex.push(&[0xF0, 2, 0xF7]).unwrap().as_ref(),
+            [0xF0, 2, 0xF7]
+        );
+    }
+
+    #[test]
+    fn ticks_do_not_drift() {
+        let mut session = new_session();
+        let start = 7_000_000;
+        let interval = 1234; // Not a multiple of usec_per_tick.
+        for i in 0..10_000u64 {
+            session.add_event(0, note_on(60), start + i * interval);
+        }
+        let ticks = absolute_ticks(&session);
+        for n in [1, 10, 999, 9_999] {
+            assert_eq!(
+                ticks[n],
+                n as u64 * interval / DEFAULT_USEC_PER_TICK as u64,
+                "event #{n}"
+            );
+        }
+    }
+
+    #[test]
+    fn ticks_never_go_backwards() {
+        let mut session = new_session();
+        session.add_event(0, note_on(60), 10_000);
+        session.add_event(0, note_on(61), 20_000);
+        session.add_event(0, note_on(62), 15_000);
+        session.add_event(0, note_on(63), 30_000);
+        assert_eq!(absolute_ticks(&session), vec![0, 20, 20, 40]);
+    }
+
+    #[test]
+    fn reset_restarts_tick_count() {
+        let mut session = new_session();
+        session.add_event(0, note_on(60), 1_000);
+        session.add_event(0, note_on(60), 101_000);
+        session.reset();
+        session.add_event(0, note_on(60), 500_000);
+        session.add_event(0, note_on(60), 501_000);
+        assert_eq!(absolute_ticks(&session), vec![0, 2]);
+    }
+
+    #[test]
+    fn split_waits_for_release() {
+        let mut session = new_session();
+        session.add_event(0, note_on(60), 1_000);
+        let later = Instant::now() + Duration::from_secs(9);
+        assert!(!session.split_due(later));
+        session.add_event(0, note_off(60), 2_000);
+        assert!(session.split_due(later));
+        assert!(!session.split_due(Instant::now()));
+    }
+
+    #[test]
+    fn split_stops_waiting_for_lost_release() {
+        let mut session = new_session();
+        session.add_event(0, note_on(60), 1_000);
+        let now = Instant::now();
+        assert!(!session.split_due(now + Duration::from_secs(60)));
+        assert!(session.split_due(now + Duration::from_secs(65)));
+    }
+
+    #[test]
+    fn held_notes_are_carried_over_split() {
+        let sustain = |value| LiveEvent::Midi {
+            channel: u4::from(1),
+            message: MidiMessage::Controller {
+                controller: u7::from(64),
+                value: u7::from(value),
+            },
+        };
+        let mut session = new_session();
+        session.add_event(0, sustain(127), 1_000);
+        session.add_event(0, note_on(60), 2_000);
+        let track = session.tracks().remove(0);
+        let ending: Vec<_> = track[track.len() - 3..track.len() - 1]
+            .iter()
+            .map(|e| e.kind)
+            .collect();
+        assert_eq!(
+            ending,
+            vec![
+                TrackEventKind::Midi {
+                    channel: u4::from(0),
+                    message: MidiMessage::NoteOff {
+                        key: u7::from(60),
+                        vel: u7::from(0)
+                    }
+                },
+                TrackEventKind::Midi {
+                    channel: u4::from(1),
+                    message: MidiMessage::Controller {
+                        controller: u7::from(64),
+                        value: u7::from(0)
+                    }
+                },
+            ]
+        );
+        drop(track);
+
+        session.reset();
+        session.add_event(0, sustain(0), 5_000);
+        let track = session.tracks().remove(0);
+        assert_eq!(track[1].kind, note_on(60).as_track_event(&Arena::new()));
+        assert_eq!(track[2].kind, sustain(127).as_track_event(&Arena::new()));
+        // Name, Note On, pedal down, pedal up, Note Off, end of track.
+        assert_eq!(track.len(), 6);
+    }
+
+    #[test]
+    fn transport_start_begins_next_take() {
+        let mut session = RecordingSession::new(
+            SplitPolicy {
+                follow_transport: true,
+                ..SplitPolicy::default()
+            },
+            vec!["Test port".to_string()],
+        );
+        let now = session.clock().now();
+        let start = LiveEvent::Realtime(SystemRealtime::Start);
+        session.add_event(0, note_on(60), 0);
+        session.add_event(0, note_off(60), 100_000);
+        session.add_event(0, start, 1_000_000);
+        session.add_event(0, note_on(62), 1_000_000);
+        assert!(session.split_due(now));
+        assert_eq!(session.event_count(), 2);
+
+        session.reset();
+        assert!(!session.split_due(now + Duration::from_secs(60)));
+        let position = midly::num::u14::from(8);
+        session.add_event(
+            0,
+            LiveEvent::Common(SystemCommon::SongPosition(position)),
+            2_000_000,
+        );
+        let texts: Vec<_> = session.tracks()[0]
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Meta(midly::MetaMessage::Marker(text)) => Some(text),
+                TrackEventKind::Meta(midly::MetaMessage::CuePoint(text)) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, vec![&b"Start"[..], b"Song Position 8"]);
+        assert_eq!(absolute_ticks(&session), vec![0, 0, 2000]);
+    }
+
+    #[test]
+    fn controller_state_is_restored() {
+        let control = |controller, value| LiveEvent::Midi {
+            channel: u4::from(2),
+            message: MidiMessage::Controller {
+                controller: u7::from(controller),
+                value: u7::from(value),
+            },
+        };
+        let mut session = new_session();
+        for (i, event) in [
+            control(7, 100),
+            LiveEvent::Midi {
+                channel: u4::from(2),
+                message: MidiMessage::ProgramChange {
+                    program: u7::from(5),
+                },
+            },
+            control(0, 1),
+            control(101, 0),
+            control(100, 0),
+            control(6, 12),
+            control(101, 127),
+            control(100, 127),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            session.add_event(0, event, i as u64 * 1_000);
+        }
+        session.reset();
+        session.add_event(0, note_off(60), 100_000);
+        let arena = Arena::new();
+        let expected: Vec<_> = [
+            control(0, 1),
+            LiveEvent::Midi {
+                channel: u4::from(2),
+                message: MidiMessage::ProgramChange {
+                    program: u7::from(5),
+                },
+            },
+            control(7, 100),
+            control(101, 0),
+            control(100, 0),
+            control(6, 12),
+            control(101, 127),
+            control(100, 127),
+            note_off(60),
+        ]
+        .iter()
+        .map(|e| e.as_track_event(&arena))
+        .collect();
+        let track = session.tracks().remove(0);
+        let actual: Vec<_> = track[1..]
+            .iter()
+            .map(|e| e.kind)
+            .take(expected.len())
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn ports_are_recorded_to_separate_tracks() {
+        let mut session = RecordingSession::new(
+            SplitPolicy::default(),
+            vec!["Keys".to_string(), "Pads".to_string()],
+        );
+        session.add_event(0, note_on(60), 1_000);
+        session.add_event(1, note_on(36), 2_000);
+        session.add_event(1, note_off(36), 3_000);
+        session.add_event(0, note_off(60), 4_000);
+        let tracks = session.tracks();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(
+            tracks[1][0].kind,
+            TrackEventKind::Meta(midly::MetaMessage::TrackName(b"Pads"))
+        );
+        let ticks = |track: &Track| -> Vec<u32> {
+            track[1..track.len() - 1]
+                .iter()
+                .map(|e| e.delta.as_int())
+                .collect()
+        };
+        assert_eq!(ticks(&tracks[0]), vec![0, 6]);
+        assert_eq!(ticks(&tracks[1]), vec![2, 2]);
+    }
+
+    #[test]
+    fn long_pauses_are_split() {
+        let usec_per_tick = DEFAULT_USEC_PER_TICK as u64;
+        let mut session = new_session();
+        for tick in [
+            0,
+            MAX_DELTA_TICKS,
+            2 * MAX_DELTA_TICKS + 1,
+            5 * MAX_DELTA_TICKS + 10,
+        ] {
+            session.add_event(0, note_off(60), tick * usec_per_tick);
+        }
+        let track = session.tracks().remove(0);
+        let deltas: Vec<u32> = track.iter().map(|e| e.delta.as_int()).collect();
+        let max = MAX_DELTA_TICKS as u32;
+        assert_eq!(deltas, vec![0, 0, max, max, 1, max, max, max, 9, 0]);
+        let notes = track
+            .iter()
+            .filter(|e| matches!(e.kind, TrackEventKind::Midi { .. }))
+            .count();
+        assert_eq!(notes, 4);
+    }
+}
@@ -0,0 +1,260 @@
+use midly::num::{u4, u7};
+use midly::{MidiMessage, PitchBend};
+use std::collections::BTreeMap;
+
+/// Sustain, sostenuto and soft pedal controllers.
+const PEDAL_CONTROLLERS: [u8; 3] = [64, 66, 67];
+
+/// Notes and pedals that are currently held down.
+#[derive(Clone)]
+pub(crate) struct HeldState {
+    /// Velocity of each held key per channel, zero if the key is not held.
+    notes: [[u8; 128]; 16],
+    /// Values of the pedal controllers per channel.
+    pedals: [[u8; PEDAL_CONTROLLERS.len()]; 16],
+}
+
+impl Default for HeldState {
+    fn default() -> Self {
+        HeldState {
+            notes: [[0; 128]; 16],
+            pedals: [[0; PEDAL_CONTROLLERS.len()]; 16],
+        }
+    }
+}
+
+impl HeldState {
+    fn update(&mut self, channel: u4, message: MidiMessage) {
+        let channel = channel.as_int() as usize;
+        match message {
+            MidiMessage::NoteOn { key, vel } => {
+                self.notes[channel][key.as_int() as usize] = vel.as_int();
+            }
+            MidiMessage::NoteOff { key, .. } => {
+                self.notes[channel][key.as_int() as usize] = 0;
+            }
+            MidiMessage::Controller { controller, value } => match controller.as_int() {
+                120 | 123 => self.notes[channel] = [0; 128], // All Sound Off, All Notes Off
+                c => {
+                    if let Some(i) = PEDAL_CONTROLLERS.iter().position(|&p| p == c) {
+                        self.pedals[channel][i] = value.as_int();
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub(crate) fn is_released(&self) -> bool {
+        self.notes.iter().flatten().all(|&v| v == 0)
+            && self.pedals.iter().flatten().all(|&v| v < 64)
+    }
+
+    /// Note Ons for the keys that are held in this state.
+    fn note_on_messages(&self, channel: u4) -> Vec<MidiMessage> {
+        self.notes[channel.as_int() as usize]
+            .iter()
+            .enumerate()
+            .filter(|(_, &vel)| vel > 0)
+            .map(|(key, &vel)| MidiMessage::NoteOn {
+                key: u7::from(key as u8),
+                vel: u7::from(vel),
+            })
+            .collect()
+    }
+
+    /// Messages that release everything that is held in this state.
+    pub(crate) fn release_messages(&self) -> Vec<(u4, MidiMessage)> {
+        let mut messages = Vec::new();
+        for channel in 0..16 {
+            for (key, &vel) in self.notes[channel].iter().enumerate() {
+                if vel > 0 {
+                    messages.push((
+                        u4::from(channel as u8),
+                        MidiMessage::NoteOff {
+                            key: u7::from(key as u8),
+                            vel: u7::from(0),
+                        },
+                    ));
+                }
+            }
+            for (i, &value) in self.pedals[channel].iter().enumerate() {
+                if value >= 64 {
+                    messages.push((
+                        u4::from(channel as u8),
+                        MidiMessage::Controller {
+                            controller: u7::from(PEDAL_CONTROLLERS[i]),
+                            value: u7::from(0),
+                        },
+                    ));
+                }
+            }
+        }
+        messages
+    }
+}
+
+/// Controllers that are not kept as plain values:
+/// data entry, (N)RPN selection and channel mode messages.
+fn is_stateless_controller(controller: u8) -> bool {
+    matches!(controller, 6 | 38 | 96..=101 | 120..=127)
+}
+
+/// Program, controller and parameter values of a MIDI channel.
+#[derive(Clone)]
+struct ChannelState {
+    program: Option<u7>,
+    controllers: [Option<u7>; 128],
+    pitch_bend: Option<PitchBend>,
+    /// Currently selected parameter: NRPN flag, number MSB and LSB.
+    parameter_nrpn: bool,
+    parameter_msb: Option<u7>,
+    parameter_lsb: Option<u7>,
+    /// Data entry MSB and LSB of RPN and NRPN parameters, keyed by NRPN flag and parameter number.
+    parameters: BTreeMap<(bool, u7, u7), (u7, Option<u7>)>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState {
+            program: None,
+            controllers: [None; 128],
+            pitch_bend: None,
+            parameter_nrpn: false,
+            parameter_msb: None,
+            parameter_lsb: None,
+            parameters: BTreeMap::new(),
+        }
+    }
+}
+
+impl ChannelState {
+    fn update(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::ProgramChange { program } => self.program = Some(program),
+            MidiMessage::PitchBend { bend } => self.pitch_bend = Some(bend),
+            MidiMessage::Controller { controller, value } => match controller.as_int() {
+                99 | 101 => {
+                    self.parameter_nrpn = controller == 99;
+                    self.parameter_msb = Some(value);
+                }
+                98 | 100 => {
+                    self.parameter_nrpn = controller == 98;
+                    self.parameter_lsb = Some(value);
+                }
+                6 => {
+                    if let Some(parameter) = self.selected_parameter() {
+                        self.parameters.insert(parameter, (value, None));
+                    }
+                }
+                38 => {
+                    if let Some(parameter) = self.selected_parameter() {
+                        if let Some(data) = self.parameters.get_mut(&parameter) {
+                            data.1 = Some(value);
+                        }
+                    }
+                }
+                121 => {
+                    // Reset All Controllers
+                    self.controllers = [None; 128];
+                    self.pitch_bend = None;
+                }
+                c if !is_stateless_controller(c) => self.controllers[c as usize] = Some(value),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn selected_parameter(&self) -> Option<(bool, u7, u7)> {
+        match (self.parameter_msb, self.parameter_lsb) {
+            (Some(msb), Some(lsb)) if !(msb == 127 && lsb == 127) => {
+                Some((self.parameter_nrpn, msb, lsb))
+            }
+            _ => None,
+        }
+    }
+
+    /// Messages that bring a receiver into this state.
+    fn restore_messages(&self) -> Vec<MidiMessage> {
+        let controller = |controller: u8, value: u7| MidiMessage::Controller {
+            controller: u7::from(controller),
+            value,
+        };
+        let mut messages = Vec::new();
+        // Bank select takes effect on the following Program Change.
+        for c in [0, 32] {
+            if let Some(value) = self.controllers[c as usize] {
+                messages.push(controller(c, value));
+            }
+        }
+        if let Some(program) = self.program {
+            messages.push(MidiMessage::ProgramChange { program });
+        }
+        for (c, value) in self.controllers.iter().enumerate() {
+            if let (Some(value), false) = (value, c == 0 || c == 32) {
+                messages.push(controller(c as u8, *value));
+            }
+        }
+        if let Some(bend) = self.pitch_bend {
+            messages.push(MidiMessage::PitchBend { bend });
+        }
+        for (&(nrpn, msb, lsb), &(data_msb, data_lsb)) in &self.parameters {
+            let (msb_controller, lsb_controller) = if nrpn { (99, 98) } else { (101, 100) };
+            messages.push(controller(msb_controller, msb));
+            messages.push(controller(lsb_controller, lsb));
+            messages.push(controller(6, data_msb));
+            if let Some(data_lsb) = data_lsb {
+                messages.push(controller(38, data_lsb));
+            }
+        }
+        if !self.parameters.is_empty() {
+            // Leave the parameter selection as it was so later data entry goes to the right place.
+            let (msb_controller, lsb_controller) = if self.parameter_nrpn {
+                (99, 98)
+            } else {
+                (101, 100)
+            };
+            let null = u7::from(127);
+            messages.push(controller(
+                msb_controller,
+                self.parameter_msb.unwrap_or(null),
+            ));
+            messages.push(controller(
+                lsb_controller,
+                self.parameter_lsb.unwrap_or(null),
+            ));
+        }
+        messages
+    }
+}
+
+/// Running state of the instrument, used to restore its sound at the beginning of each file.
+#[derive(Clone, Default)]
+pub(crate) struct InstrumentState {
+    channels: [ChannelState; 16],
+    pub(crate) held: HeldState,
+}
+
+impl InstrumentState {
+    pub(crate) fn update(&mut self, channel: u4, message: MidiMessage) {
+        self.channels[channel.as_int() as usize].update(message);
+        self.held.update(channel, message);
+    }
+
+    /// Messages that bring a receiver into this state.
+    pub(crate) fn restore_messages(&self) -> Vec<(u4, MidiMessage)> {
+        let mut messages = Vec::new();
+        for (i, state) in self.channels.iter().enumerate() {
+            let channel = u4::from(i as u8);
+            for message in state
+                .restore_messages()
+                .into_iter()
+                .chain(self.held.note_on_messages(channel))
+            {
+                messages.push((channel, message));
+            }
+        }
+        messages
+    }
+}
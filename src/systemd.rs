@@ -0,0 +1,187 @@
+use std::ffi::OsStr;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+use std::{env, io};
+
+/// Reports service state to systemd via the sd_notify protocol.
+/// Does nothing when the program is not started by systemd with `Type=notify`.
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog_interval: Option<Duration>,
+    last_watchdog: Option<Instant>,
+    last_status: String,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let watchdog_pid_matches = env::var("WATCHDOG_PID")
+            .map(|pid| pid.parse() == Ok(std::process::id()))
+            .unwrap_or(true);
+        let watchdog_interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| watchdog_pid_matches)
+            .map(|usec: u64| Duration::from_micros(usec / 2));
+        Self::new(env::var_os("NOTIFY_SOCKET").as_deref(), watchdog_interval)
+    }
+
+    /// Notifies the socket at the path, an abstract one if it starts with `@`,
+    /// and pings the watchdog at the interval.
+    pub fn new(socket_path: Option<&OsStr>, watchdog_interval: Option<Duration>) -> Self {
+        let socket = socket_path.and_then(|path| {
+            let address = match path.as_encoded_bytes().strip_prefix(b"@") {
+                Some(name) => abstract_address(name),
+                None => SocketAddr::from_pathname(path),
+            };
+            match address.and_then(|a| Ok((UnixDatagram::unbound()?, a))) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    eprintln!("Cannot open systemd notification socket: {}", e);
+                    None
+                }
+            }
+        });
+        Notifier {
+            socket,
+            watchdog_interval,
+            last_watchdog: None,
+            last_status: String::new(),
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((socket, address)) = &self.socket {
+            if let Err(e) = socket.send_to_addr(state.as_bytes(), address) {
+                eprintln!("Cannot notify systemd: {}", e);
+            }
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Sets the status line shown by `systemctl status`, unchanged status is not resent.
+    pub fn status(&mut self, status: &str) {
+        if self.last_status != status {
+            self.notify(&format!("STATUS={}", status));
+            self.last_status = status.to_string();
+        }
+    }
+
+    /// Tells the systemd watchdog that the program is alive.
+    pub fn watchdog(&mut self) {
+        let Some(interval) = self.watchdog_interval else {
+            return;
+        };
+        let now = Instant::now();
+        if self
+            .last_watchdog
+            .is_none_or(|t| now.duration_since(t) >= interval)
+        {
+            self.notify("WATCHDOG=1");
+            self.last_watchdog = Some(now);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_address(name: &[u8]) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_address(_name: &[u8]) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Abstract socket addresses are only supported on Linux",
+    ))
+}
+
+/// Text of a unit file that runs the recorder with the given command line.
+pub fn unit_file(command: &[String], system: bool, user: Option<&str>) -> String {
+    let exec_start: Vec<String> = command.iter().map(|arg| quote(arg)).collect();
+    let mut unit = format!(
+        "[Unit]\n\
+         Description={}\n\
+         After=sound.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         NotifyAccess=main\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\
+         WatchdogSec=30\n\
+         # The recorder waits for MIDI devices to appear.\n\
+         TimeoutStartSec=infinity\n",
+        env!("CARGO_PKG_DESCRIPTION"),
+        exec_start.join(" ")
+    );
+    if system {
+        if let Some(user) = user {
+            unit.push_str(&format!("User={}\n", user));
+        }
+        unit.push_str("SupplementaryGroups=audio\n");
+    }
+    unit.push_str(&format!(
+        "\n[Install]\nWantedBy={}\n",
+        if system {
+            "multi-user.target"
+        } else {
+            "default.target"
+        }
+    ));
+    unit
+}
+
+/// Quotes a command line argument for `ExecStart=`.
+fn quote(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PACKAGE_NAME;
+
+    #[test]
+    fn systemd_is_notified() {
+        let path = env::temp_dir().join(format!(
+            "{}-test-notify-{}",
+            PACKAGE_NAME,
+            std::process::id()
+        ));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let mut notifier = Notifier::new(Some(path.as_os_str()), None);
+        notifier.ready();
+        notifier.status("Recording");
+        notifier.status("Recording");
+        notifier.stopping();
+        let mut received = Vec::new();
+        let mut buffer = [0; 64];
+        for _ in 0..3 {
+            let n = socket.recv(&mut buffer).unwrap();
+            received.push(String::from_utf8_lossy(&buffer[..n]).into_owned());
+        }
+        assert_eq!(received, vec!["READY=1", "STATUS=Recording", "STOPPING=1"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
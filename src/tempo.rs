@@ -0,0 +1,338 @@
+/// MIDI clock pulses per quarter note.
+const PULSES_PER_BEAT: u64 = 24;
+/// Song Position Pointer counts sixteenth notes.
+const PULSES_PER_SIXTEENTH: u64 = 6;
+/// A clock that sends no pulse for this long has stopped, in microseconds.
+/// Even at 20 BPM pulses are 125 ms apart.
+const CLOCK_TIMEOUT: u64 = 500_000;
+/// Tempo changes smaller than this are jitter of the clock source.
+const TEMPO_TOLERANCE: f64 = 0.01;
+/// Weight of a new pulse interval in the smoothed interval.
+const SMOOTHING: f64 = 0.25;
+/// Tempo of a file without tempo events.
+pub(crate) const DEFAULT_USEC_PER_BEAT: u32 = 500_000;
+
+/// MIDI clock that is being followed, it is kept between takes.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ClockState {
+    pub(crate) port: usize,
+    /// Timestamp of the last pulse.
+    pub(crate) last_pulse: u64,
+    /// Pulses since the start of the beat.
+    pub(crate) phase: u64,
+    /// Timestamp of the pulse that started the beat, unless the clock started within the beat.
+    pub(crate) beat_start: Option<u64>,
+    /// Smoothed interval between pulses in microseconds, once two pulses have arrived.
+    pub(crate) usec_per_pulse: Option<f64>,
+}
+
+impl ClockState {
+    fn is_running(&self, timestamp: u64) -> bool {
+        timestamp.saturating_sub(self.last_pulse) <= CLOCK_TIMEOUT
+    }
+}
+
+/// Tick positions of the take in progress.
+struct TakeTicks {
+    /// Timestamp and tick that later ticks are counted from.
+    anchor: (u64, f64),
+    usec_per_tick: f64,
+    /// Ticks do not go past the tick of the next pulse, in case it is late.
+    /// The limit is lifted at the timestamp, when the clock is considered stopped.
+    limit: Option<(f64, u64)>,
+    /// Tick of the beat in progress, while the clock runs.
+    beat_tick: Option<f64>,
+    /// Tick and microseconds per beat of each tempo change.
+    tempo_changes: Vec<(u64, u32)>,
+}
+
+/// Converts event timestamps into ticks of the take. Without MIDI clock the tempo is fixed.
+/// While a clock runs, its beats fall on whole beats of the file, ticks between pulses are
+/// interpolated, and the tempo of each beat is measured from its pulses.
+pub(crate) struct TickMap {
+    ticks_per_beat: f64,
+    default_usec_per_tick: f64,
+    clock: Option<ClockState>,
+    take: Option<TakeTicks>,
+    /// Pulses into the beat of the next pulse, after a transport start or song position.
+    next_phase: Option<u64>,
+}
+
+impl TickMap {
+    pub(crate) fn new(ticks_per_beat: u16) -> Self {
+        TickMap {
+            ticks_per_beat: ticks_per_beat as f64,
+            default_usec_per_tick: DEFAULT_USEC_PER_BEAT as f64 / ticks_per_beat as f64,
+            clock: None,
+            take: None,
+            next_phase: None,
+        }
+    }
+
+    fn ticks_per_pulse(&self) -> f64 {
+        self.ticks_per_beat / PULSES_PER_BEAT as f64
+    }
+
+    /// Tick of an event, the first event starts the take.
+    pub(crate) fn tick(&mut self, timestamp: u64) -> u64 {
+        self.exact_tick(timestamp) as u64
+    }
+
+    fn exact_tick(&mut self, timestamp: u64) -> f64 {
+        if self.take.is_none() {
+            self.start_take(timestamp);
+        }
+        let take = self.take.as_ref().unwrap();
+        let (anchor_time, anchor_tick) = take.anchor;
+        let tick = anchor_tick + timestamp.saturating_sub(anchor_time) as f64 / take.usec_per_tick;
+        match take.limit {
+            Some((limit, until)) if timestamp <= until => tick.min(limit),
+            _ => tick,
+        }
+    }
+
+    fn start_take(&mut self, timestamp: u64) {
+        let mut take = TakeTicks {
+            anchor: (timestamp, 0.0),
+            usec_per_tick: self.default_usec_per_tick,
+            limit: None,
+            beat_tick: None,
+            tempo_changes: Vec::new(),
+        };
+        let clock = self.clock.as_ref().filter(|c| c.is_running(timestamp));
+        if self.next_phase.is_some() {
+            // The transport starts with the take, wait for the next pulse.
+            take.limit = Some((0.0, timestamp + CLOCK_TIMEOUT));
+        } else if let Some(clock) = clock {
+            // The take starts at the beginning of the current beat.
+            take.anchor = (
+                clock.last_pulse,
+                clock.phase as f64 * self.ticks_per_pulse(),
+            );
+            take.limit = Some((
+                take.anchor.1 + self.ticks_per_pulse(),
+                clock.last_pulse + CLOCK_TIMEOUT,
+            ));
+            take.beat_tick = Some(0.0);
+        }
+        if let Some(usec_per_pulse) = clock.and_then(|c| c.usec_per_pulse) {
+            take.usec_per_tick = usec_per_pulse / self.ticks_per_pulse();
+            take.set_tempo(0, usec_per_pulse * PULSES_PER_BEAT as f64);
+        }
+        self.take = Some(take);
+    }
+
+    /// Places the next clock pulse at the song position, in sixteenth notes since the start
+    /// of the song. Ticks wait for that pulse.
+    pub(crate) fn set_song_position(&mut self, timestamp: u64, sixteenths: u64) {
+        self.next_phase = Some(sixteenths * PULSES_PER_SIXTEENTH % PULSES_PER_BEAT);
+        if self.take.is_some() {
+            let tick = self.exact_tick(timestamp);
+            let take = self.take.as_mut().unwrap();
+            take.anchor = (timestamp, tick);
+            take.limit = Some((tick, timestamp + CLOCK_TIMEOUT));
+        }
+    }
+
+    /// Follows a MIDI clock pulse from the port. Only one clock is followed at a time.
+    pub(crate) fn pulse(&mut self, port: usize, timestamp: u64) {
+        let ticks_per_pulse = self.ticks_per_pulse();
+        let usec_per_pulse = match self.clock.as_mut().filter(|c| c.is_running(timestamp)) {
+            Some(clock) if clock.port != port => return,
+            Some(clock) => {
+                let interval = timestamp.saturating_sub(clock.last_pulse) as f64;
+                Some(match clock.usec_per_pulse {
+                    Some(smoothed) => smoothed + SMOOTHING * (interval - smoothed),
+                    None => interval,
+                })
+            }
+            None => None,
+        };
+        let restart_phase = match (self.next_phase.take(), usec_per_pulse) {
+            (Some(phase), _) => Some(phase),
+            (None, None) => Some(0),
+            (None, Some(_)) => None,
+        };
+        if let Some(phase) = restart_phase {
+            self.start_beat_grid(port, timestamp, phase, usec_per_pulse);
+            return;
+        }
+        let usec_per_pulse = usec_per_pulse.unwrap();
+        let clock = self.clock.as_mut().unwrap();
+        clock.usec_per_pulse = Some(usec_per_pulse);
+        clock.last_pulse = timestamp;
+        clock.phase += 1;
+        // The tempo of the beat is estimated from its pulses so far, and set when it ends.
+        let usec_per_beat = match clock.beat_start {
+            Some(start) => (timestamp - start) as f64 / clock.phase as f64 * PULSES_PER_BEAT as f64,
+            None => usec_per_pulse * PULSES_PER_BEAT as f64,
+        };
+        let beat_ended = clock.phase == PULSES_PER_BEAT;
+        if beat_ended {
+            clock.phase = 0;
+            clock.beat_start = Some(timestamp);
+        }
+        let phase = clock.phase;
+
+        let Some(take) = &mut self.take else {
+            return;
+        };
+        let Some(beat_tick) = take.beat_tick else {
+            return;
+        };
+        take.set_tempo(beat_tick.max(0.0) as u64, usec_per_beat);
+        let beat_tick = if beat_ended {
+            beat_tick + self.ticks_per_beat
+        } else {
+            beat_tick
+        };
+        take.beat_tick = Some(beat_tick);
+        take.anchor = (timestamp, beat_tick + phase as f64 * ticks_per_pulse);
+        take.limit = Some((take.anchor.1 + ticks_per_pulse, timestamp + CLOCK_TIMEOUT));
+        take.usec_per_tick = usec_per_pulse / ticks_per_pulse;
+    }
+
+    /// Starts following a clock that starts, or jumps to another position, at the pulse.
+    /// The take continues on the next tick where the pulse fits the beats of the file.
+    fn start_beat_grid(
+        &mut self,
+        port: usize,
+        timestamp: u64,
+        phase: u64,
+        usec_per_pulse: Option<f64>,
+    ) {
+        self.clock = Some(ClockState {
+            port,
+            last_pulse: timestamp,
+            phase,
+            beat_start: (phase == 0).then_some(timestamp),
+            usec_per_pulse,
+        });
+        if self.take.is_some() {
+            let tick = self.exact_tick(timestamp);
+            let ticks_per_beat = self.ticks_per_beat;
+            let ticks_per_pulse = self.ticks_per_pulse();
+            let take = self.take.as_mut().unwrap();
+            let phase_ticks = phase as f64 * ticks_per_pulse;
+            let beat_tick = ((tick - phase_ticks) / ticks_per_beat).ceil() * ticks_per_beat;
+            take.beat_tick = Some(beat_tick);
+            take.anchor = (timestamp, beat_tick + phase_ticks);
+            take.limit = Some((take.anchor.1 + ticks_per_pulse, timestamp + CLOCK_TIMEOUT));
+            if let Some(usec_per_pulse) = usec_per_pulse {
+                take.usec_per_tick = usec_per_pulse / ticks_per_pulse;
+            }
+        }
+    }
+
+    /// The clock that is being followed, if any.
+    pub(crate) fn clock(&self) -> Option<&ClockState> {
+        self.clock.as_ref()
+    }
+
+    /// Follows a clock from where it was, e.g. when a journal is recovered.
+    pub(crate) fn resume_clock(&mut self, clock: ClockState) {
+        self.clock = Some(clock);
+    }
+
+    /// Tempo changes of the take, as tick and microseconds per beat.
+    pub(crate) fn tempo_changes(&self) -> &[(u64, u32)] {
+        self.take.as_ref().map_or(&[], |t| &t.tempo_changes)
+    }
+
+    /// Ends the take, the clock keeps being followed.
+    pub(crate) fn reset(&mut self) {
+        self.take = None;
+    }
+}
+
+impl TakeTicks {
+    /// Sets the tempo from the tick on, replacing an earlier estimate for the same tick.
+    fn set_tempo(&mut self, tick: u64, usec_per_beat: f64) {
+        if self.tempo_changes.last().is_some_and(|c| c.0 == tick) {
+            self.tempo_changes.pop();
+        }
+        let current = self
+            .tempo_changes
+            .last()
+            .map_or(DEFAULT_USEC_PER_BEAT, |c| c.1) as f64;
+        if (usec_per_beat - current).abs() > current * TEMPO_TOLERANCE {
+            // Tempo meta events hold 24 bits.
+            let usec_per_beat = usec_per_beat.round().min(0xFF_FFFF as f64) as u32;
+            self.tempo_changes.push((tick, usec_per_beat));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_tempo_without_clock() {
+        let mut tempo = TickMap::new(1000);
+        assert_eq!(tempo.tick(7_000), 0);
+        assert_eq!(tempo.tick(8_234), 2);
+        assert_eq!(tempo.tick(1_007_000), 2000);
+        assert!(tempo.tempo_changes().is_empty());
+    }
+
+    #[test]
+    fn clock_jitter_does_not_change_tempo() {
+        let mut tempo = TickMap::new(1000);
+        // 100 BPM, pulses arrive up to a millisecond early or late.
+        let pulse_time = |n: u64| n * 25_000 + [0, 1_000, 0, 0, 900][n as usize % 5];
+        let mut ticks = Vec::new();
+        for n in 0..4 * PULSES_PER_BEAT {
+            tempo.pulse(0, pulse_time(n));
+            if n % PULSES_PER_BEAT == 0 {
+                ticks.push(tempo.tick(pulse_time(n)));
+            }
+        }
+        assert_eq!(ticks, vec![0, 1000, 2000, 3000]);
+        // Set by the first beat, which was a little long.
+        assert_eq!(tempo.tempo_changes(), &[(0, 600_900)]);
+
+        // The take after a split starts on the beat too.
+        tempo.reset();
+        tempo.pulse(0, pulse_time(96));
+        assert_eq!(tempo.tick(pulse_time(96) + 12_500), 20);
+        let usec_per_beat = tempo.tempo_changes()[0].1;
+        assert!((594_000..606_000).contains(&usec_per_beat));
+    }
+
+    #[test]
+    fn clock_that_starts_during_take_continues_on_next_beat() {
+        let mut tempo = TickMap::new(1000);
+        assert_eq!(tempo.tick(0), 0);
+        // 150 BPM from 0.3 s, which is tick 600 at the default tempo.
+        for n in 0..=PULSES_PER_BEAT {
+            tempo.pulse(0, 300_000 + n * 16_667);
+        }
+        assert_eq!(tempo.tick(300_000 + 24 * 16_667), 2000);
+        assert_eq!(tempo.tempo_changes(), &[(1000, 400_008)]);
+        // Clock stops, ticks go on at the last tempo.
+        assert_eq!(tempo.tick(300_000 + 24 * 16_667 + 801_000), 4002);
+    }
+
+    #[test]
+    fn song_position_moves_beat_grid() {
+        let mut tempo = TickMap::new(1000);
+        // 100 BPM, the take starts on a beat.
+        tempo.pulse(0, 0);
+        assert_eq!(tempo.tick(0), 0);
+        for n in 1..=30 {
+            tempo.pulse(0, n * 25_000);
+        }
+        assert_eq!(tempo.tick(30 * 25_000), 1250);
+        // Jump to the middle of a beat, ticks wait for the next pulse, which comes late.
+        tempo.set_song_position(30 * 25_000 + 5_000, 4 * 16 + 2);
+        assert_eq!(tempo.tick(31 * 25_000 + 5_000), 1258);
+        tempo.pulse(0, 31 * 25_000 + 10_000);
+        assert_eq!(tempo.tick(31 * 25_000 + 10_000), 1500);
+        for n in 32..=43 {
+            tempo.pulse(0, n * 25_000 + 10_000);
+        }
+        assert_eq!(tempo.tick(43 * 25_000 + 10_000), 2000);
+        assert_eq!(tempo.tempo_changes(), &[(0, 600_000)]);
+    }
+}
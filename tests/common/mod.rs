@@ -0,0 +1,58 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use chrono::{Local, TimeZone};
+use midi_blackbox::{ManualClock, RecordingSession, TakeSink};
+use midly::TrackEventKind;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// File name and contents.
+pub type SavedFile = (String, Vec<u8>);
+
+/// Keeps saved files in memory.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    pub files: Arc<Mutex<Vec<SavedFile>>>,
+}
+
+impl MemorySink {
+    pub fn file_names(&self) -> Vec<String> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.0.clone())
+            .collect()
+    }
+}
+
+impl TakeSink for MemorySink {
+    fn save(&mut self, session: &mut RecordingSession, _now: Instant) -> bool {
+        let file_time = session.clock().wall_time();
+        session
+            .save_take(file_time, |name, data| {
+                let file = (name.to_string(), data.to_vec());
+                self.files.lock().unwrap().push(file);
+                Ok(())
+            })
+            .is_ok()
+    }
+}
+
+pub fn clock() -> ManualClock {
+    ManualClock::new(Local.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap())
+}
+
+/// Channel messages of the track with their absolute ticks.
+pub fn midi_events(track: &[midly::TrackEvent]) -> Vec<(u64, TrackEventKind<'static>)> {
+    let mut tick = 0;
+    let mut result = Vec::new();
+    for event in track {
+        tick += event.delta.as_int() as u64;
+        if let TrackEventKind::Midi { channel, message } = event.kind {
+            result.push((tick, TrackEventKind::Midi { channel, message }));
+        }
+    }
+    result
+}
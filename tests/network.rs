@@ -0,0 +1,408 @@
+mod common;
+
+use common::{clock, midi_events, MemorySink};
+use midi_blackbox::{
+    IpMidiListener, OscListener, Recorder, RtpMidiListener, TcpMidiListener, IPMIDI_GROUP,
+};
+use midly::num::u7;
+use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Polls the recorder until the take has the number of events.
+fn wait_for_events(recorder: &mut Recorder, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while recorder.status().take_events < count {
+        assert!(Instant::now() < deadline, "{:?}", recorder.status());
+        recorder.poll().unwrap();
+        sleep(Duration::from_millis(10));
+    }
+}
+
+fn note_messages(smf: &Smf, track: usize) -> Vec<(u64, MidiMessage)> {
+    midi_events(&smf.tracks[track])
+        .into_iter()
+        .filter_map(|(tick, kind)| match kind {
+            TrackEventKind::Midi { message, .. } => Some((tick, message)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn note_on(key: u8, vel: u8) -> MidiMessage {
+    MidiMessage::NoteOn {
+        key: u7::from(key),
+        vel: u7::from(vel),
+    }
+}
+
+fn note_off(key: u8) -> MidiMessage {
+    MidiMessage::NoteOff {
+        key: u7::from(key),
+        vel: u7::from(0),
+    }
+}
+
+/// Minimal AppleMIDI session initiator, as a laptop or an iPad would be.
+struct RtpMidiPeer {
+    control: UdpSocket,
+    data: UdpSocket,
+    listener: SocketAddr,
+    ssrc: u32,
+    sequence: u16,
+    start: Instant,
+}
+
+impl RtpMidiPeer {
+    fn join(port: u16, name: &str, ssrc: u32) -> Self {
+        let socket = || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            socket
+        };
+        let peer = RtpMidiPeer {
+            control: socket(),
+            data: socket(),
+            listener: SocketAddr::from(([127, 0, 0, 1], port)),
+            ssrc,
+            sequence: 0,
+            start: Instant::now(),
+        };
+        let invitation = peer.handshake(b"IN", name);
+        for (socket, port) in [(&peer.control, port), (&peer.data, port + 1)] {
+            let address = SocketAddr::from(([127, 0, 0, 1], port));
+            socket.send_to(&invitation, address).unwrap();
+            let reply = Self::receive(socket);
+            assert_eq!(&reply[..4], b"\xFF\xFFOK");
+            assert_eq!(reply[8..12], invitation[8..12], "initiator token");
+        }
+
+        let mut sync = [0; 36];
+        sync[..4].copy_from_slice(b"\xFF\xFFCK");
+        sync[4..8].copy_from_slice(&ssrc.to_be_bytes());
+        sync[12..20].copy_from_slice(&peer.clock().to_be_bytes());
+        peer.data.send_to(&sync, peer.data_address()).unwrap();
+        let mut sync = Self::receive(&peer.data);
+        assert_eq!((&sync[..4], sync[8]), (&b"\xFF\xFFCK"[..], 1));
+        sync[4..8].copy_from_slice(&ssrc.to_be_bytes());
+        sync[8] = 2;
+        sync[28..36].copy_from_slice(&peer.clock().to_be_bytes());
+        peer.data.send_to(&sync, peer.data_address()).unwrap();
+        peer
+    }
+
+    fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buffer = [0; 1500];
+        let length = socket.recv(&mut buffer).unwrap();
+        buffer[..length].to_vec()
+    }
+
+    fn data_address(&self) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], self.listener.port() + 1))
+    }
+
+    /// Session clock in units of 100 µs, this one starts at a different time than the listener's.
+    fn clock(&self) -> u64 {
+        1_000_000 + self.start.elapsed().as_micros() as u64 / 100
+    }
+
+    fn handshake(&self, command: &[u8], name: &str) -> Vec<u8> {
+        let mut packet = b"\xFF\xFF".to_vec();
+        packet.extend_from_slice(command);
+        packet.extend_from_slice(&2u32.to_be_bytes());
+        packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(name.as_bytes());
+        packet.push(0);
+        packet
+    }
+
+    /// Builds the next RTP packet with a MIDI command list and a recovery journal.
+    fn packet(&mut self, commands: &[u8], journal: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, 0x61];
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&(self.clock() as u32).to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        let journal_flag = if journal.is_empty() { 0 } else { 0x40 };
+        packet.push(0x80 | journal_flag | (commands.len() >> 8) as u8);
+        packet.push(commands.len() as u8);
+        packet.extend_from_slice(commands);
+        packet.extend_from_slice(journal);
+        self.sequence += 1;
+        packet
+    }
+
+    fn send(&mut self, commands: &[u8], journal: &[u8]) {
+        let packet = self.packet(commands, journal);
+        self.data.send_to(&packet, self.data_address()).unwrap();
+    }
+
+    fn leave(&self) {
+        let bye = self.handshake(b"BY", "");
+        self.control.send_to(&bye[..16], self.listener).unwrap();
+    }
+}
+
+#[test]
+fn rtp_midi_session_is_recorded() {
+    let listener = RtpMidiListener::new("Blackbox".to_string(), 0, false).unwrap();
+    let port = listener.port();
+    let sink = MemorySink::default();
+    let mut recorder = Recorder::builder(listener, sink.clone())
+        .clock(clock())
+        .build();
+    recorder.poll().unwrap();
+
+    let mut peer = RtpMidiPeer::join(port, "Laptop", 0xABCD);
+    assert_eq!(recorder.status().connected_inputs, 1);
+    peer.send(&[0x90, 60, 100], &[]);
+    // The next packet is lost on the way, it releases the note and starts another.
+    peer.packet(&[0x80, 60, 0, 0, 0x90, 62, 90], &[]);
+    // Its journal tells that note 62 is on and note 60 was released.
+    let journal = [
+        0x20, 0, 1, // Journal header: one channel journal
+        0x00, 8, 0x08, // Channel 0 with chapter N
+        0x01, 0x77, 62, 90, 0x08, // Note 62 on, offbits of notes 56..63 with note 60 set
+    ];
+    peer.send(&[0x90, 64, 80], &journal);
+    // Note off, and a second note off with 1 second of delta time.
+    peer.send(&[0x80, 62, 0, 0xCE, 0x10, 64, 0], &[]);
+    wait_for_events(&mut recorder, 6);
+    // Receiver feedback for the packet with the journal.
+    let feedback = RtpMidiPeer::receive(&peer.control);
+    assert_eq!(
+        (&feedback[..4], &feedback[8..10]),
+        (&b"\xFF\xFFRS"[..], &[0, 2][..])
+    );
+    peer.leave();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while recorder.status().connected_inputs > 0 {
+        assert!(Instant::now() < deadline);
+        sleep(Duration::from_millis(10));
+    }
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[0].1).unwrap();
+    assert_eq!(
+        smf.tracks[0][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Laptop (RTP-MIDI)"))
+    );
+    let messages = note_messages(&smf, 0);
+    let notes: Vec<_> = messages.iter().map(|m| m.1).collect();
+    assert_eq!(
+        notes,
+        vec![
+            note_on(60, 100),
+            note_off(60),
+            note_on(62, 90),
+            note_on(64, 80),
+            note_off(62),
+            note_off(64),
+        ]
+    );
+    // 10000 units of 100 µs at 500 µs per tick.
+    assert_eq!(messages[5].0 - messages[4].0, 2000);
+}
+
+#[test]
+fn midi_over_tcp_is_recorded() {
+    let listener = TcpMidiListener::new(0, false).unwrap();
+    let port = listener.port();
+    let sink = MemorySink::default();
+    let mut recorder = Recorder::builder(listener, sink.clone())
+        .clock(clock())
+        .build();
+    recorder.poll().unwrap();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    // Running status and a SysEx message that arrives in two parts.
+    stream
+        .write_all(&[0x90, 60, 100, 64, 100, 0xF0, 0x7E])
+        .unwrap();
+    stream.flush().unwrap();
+    sleep(Duration::from_millis(50));
+    stream
+        .write_all(&[0x7F, 0x06, 0x01, 0xF7, 0x80, 60])
+        .unwrap();
+    wait_for_events(&mut recorder, 3);
+    assert_eq!(recorder.status().connected_inputs, 1);
+    // The connection drops while the note is held.
+    drop(stream);
+    wait_for_events(&mut recorder, 5);
+    assert_eq!(recorder.status().connected_inputs, 0);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[0].1).unwrap();
+    assert_eq!(
+        smf.tracks[0][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"127.0.0.1 (TCP)"))
+    );
+    assert!(smf.tracks[0]
+        .iter()
+        .any(|e| e.kind == TrackEventKind::SysEx(&[0x7E, 0x7F, 0x06, 0x01, 0xF7])));
+    let notes: Vec<_> = note_messages(&smf, 0).iter().map(|m| m.1).collect();
+    assert_eq!(
+        notes,
+        vec![
+            note_on(60, 100),
+            note_on(64, 100),
+            note_off(60),
+            note_off(64)
+        ]
+    );
+}
+
+#[test]
+fn tcp_connections_get_separate_tracks() {
+    let listener = TcpMidiListener::new(0, false).unwrap();
+    let port = listener.port();
+    let sink = MemorySink::default();
+    let mut recorder = Recorder::builder(listener, sink.clone())
+        .clock(clock())
+        .build();
+    recorder.poll().unwrap();
+
+    // Two scripts on the same host send at once.
+    let mut keys = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    keys.write_all(&[0x90, 60, 100]).unwrap();
+    wait_for_events(&mut recorder, 1);
+    let mut pads = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    pads.write_all(&[0x99, 36, 120, 0x89, 36, 0]).unwrap();
+    wait_for_events(&mut recorder, 3);
+    assert_eq!(recorder.status().connected_inputs, 2);
+    keys.write_all(&[0x80, 60, 0]).unwrap();
+    wait_for_events(&mut recorder, 4);
+    drop(keys);
+    drop(pads);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[0].1).unwrap();
+    assert_eq!(smf.tracks.len(), 2);
+    assert_eq!(
+        smf.tracks[1][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"127.0.0.1 #2 (TCP)"))
+    );
+    let notes: Vec<_> = note_messages(&smf, 0).iter().map(|m| m.1).collect();
+    assert_eq!(notes, vec![note_on(60, 100), note_off(60)]);
+    assert_eq!(note_messages(&smf, 1).len(), 2);
+}
+
+#[test]
+fn ipmidi_senders_get_separate_tracks() {
+    let listener = IpMidiListener::new(IPMIDI_GROUP, 0, false).unwrap();
+    let address = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+    let sink = MemorySink::default();
+    let mut recorder = Recorder::builder(listener, sink.clone())
+        .clock(clock())
+        .build();
+    recorder.poll().unwrap();
+
+    let keys = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let pads = UdpSocket::bind("127.0.0.2:0").unwrap();
+    keys.send_to(&[0x90, 60, 100], address).unwrap();
+    wait_for_events(&mut recorder, 1);
+    pads.send_to(&[0x99, 36, 120, 0x89, 36, 0], address)
+        .unwrap();
+    wait_for_events(&mut recorder, 3);
+    keys.send_to(&[0x80, 60, 0], address).unwrap();
+    wait_for_events(&mut recorder, 4);
+    assert_eq!(recorder.status().connected_inputs, 2);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[0].1).unwrap();
+    assert_eq!(smf.tracks.len(), 2);
+    let name = format!("{} (ipMIDI)", pads.local_addr().unwrap());
+    assert_eq!(
+        smf.tracks[1][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes()))
+    );
+    assert_eq!(note_messages(&smf, 0).len(), 2);
+    assert_eq!(note_messages(&smf, 1).len(), 2);
+}
+
+/// OSC message with int and float arguments.
+fn osc_message(address: &str, arguments: &[f32]) -> Vec<u8> {
+    let padded = |packet: &mut Vec<u8>, text: &str| {
+        packet.extend_from_slice(text.as_bytes());
+        packet.resize((packet.len() + 4) / 4 * 4, 0);
+    };
+    let mut packet = Vec::new();
+    padded(&mut packet, address);
+    let tags: String = arguments
+        .iter()
+        .map(|a| if a.fract() == 0.0 { 'i' } else { 'f' })
+        .collect();
+    padded(&mut packet, &format!(",{}", tags));
+    for &argument in arguments {
+        if argument.fract() == 0.0 {
+            packet.extend_from_slice(&(argument as i32).to_be_bytes());
+        } else {
+            packet.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+    packet
+}
+
+#[test]
+fn osc_messages_are_recorded() {
+    let mappings = vec![
+        "/note channel pitch velocity".parse().unwrap(),
+        "/1/fader1 controller=7 value".parse().unwrap(),
+    ];
+    let listener = OscListener::new(0, mappings, false).unwrap();
+    let address = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+    let sink = MemorySink::default();
+    let mut recorder = Recorder::builder(listener, sink.clone())
+        .clock(clock())
+        .build();
+    recorder.poll().unwrap();
+
+    // Senders of no MIDI get no track.
+    let pinger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    pinger.send_to(&osc_message("/ping", &[]), address).unwrap();
+    pinger.send_to(b"not OSC", address).unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender
+        .send_to(&osc_message("/note", &[1.0, 60.0, 100.0]), address)
+        .unwrap();
+    sender
+        .send_to(&osc_message("/1/fader1", &[0.25]), address)
+        .unwrap();
+    sender
+        .send_to(&osc_message("/unmapped", &[1.0]), address)
+        .unwrap();
+    let mut midi = b"/midi\0\0\0,m\0\0".to_vec();
+    midi.extend_from_slice(&[0, 0x80, 60, 0]);
+    sender.send_to(&midi, address).unwrap();
+    wait_for_events(&mut recorder, 3);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[0].1).unwrap();
+    let name = format!("{} (OSC)", sender.local_addr().unwrap());
+    assert_eq!(smf.tracks.len(), 1);
+    assert_eq!(
+        smf.tracks[0][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes()))
+    );
+    let messages: Vec<_> = note_messages(&smf, 0).iter().map(|m| m.1).collect();
+    assert_eq!(
+        messages,
+        vec![
+            note_on(60, 100),
+            MidiMessage::Controller {
+                controller: u7::from(7),
+                value: u7::from(32),
+            },
+            note_off(60),
+        ]
+    );
+}
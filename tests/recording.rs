@@ -0,0 +1,547 @@
+mod common;
+
+use chrono::Local;
+use common::{clock, midi_events, MemorySink};
+use midi_blackbox::{
+    Archive, Controls, ManualClock, MidiSource, Recorder, ScriptedEvent, ScriptedSource,
+    SharedSession, SplitPolicy,
+};
+use midly::{MetaMessage, Smf, TrackEventKind};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+fn event(seconds: f64, bytes: &[u8]) -> ScriptedEvent {
+    ScriptedEvent {
+        timestamp: (seconds * 1e6) as u64,
+        track: 0,
+        bytes: bytes.to_vec(),
+    }
+}
+
+fn note(seconds: f64, key: u8, length: f64) -> [ScriptedEvent; 2] {
+    [
+        event(seconds, &[0x90, key, 100]),
+        event(seconds + length, &[0x80, key, 0]),
+    ]
+}
+
+/// Polls the recorder once a second of clock time.
+fn run(recorder: &mut Recorder, clock: &ManualClock, seconds: u64) {
+    for _ in 0..seconds {
+        recorder.poll().unwrap();
+        clock.advance(Duration::from_secs(1));
+    }
+}
+
+fn temp_directory(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("midi-blackbox-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    directory
+}
+
+#[test]
+fn takes_are_split_on_pause() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let script: Vec<_> = [note(0.0, 60, 0.5), note(1.0, 62, 0.5), note(20.0, 64, 1.0)].concat();
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .build();
+
+    run(&mut recorder, &clock, 40);
+    assert!(recorder.finish());
+
+    // The first take ends 8 seconds after its last event arrives at the 2nd second.
+    assert_eq!(
+        sink.file_names(),
+        vec![
+            "2024-01-02_03:04:16-6e-2s.mid",
+            "2024-01-02_03:04:35-4e-1s.mid"
+        ]
+    );
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[1].1).unwrap();
+    assert_eq!(
+        smf.tracks[0][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Keys"))
+    );
+    let ticks: Vec<u64> = midi_events(&smf.tracks[0]).iter().map(|e| e.0).collect();
+    assert_eq!(ticks, vec![0, 2000]);
+}
+
+#[test]
+fn scripted_events_need_a_track() {
+    let mut script = note(0.0, 60, 0.5).to_vec();
+    script[1].track = 1;
+    assert!(ScriptedSource::new(vec!["Keys".to_string()], script).is_err());
+}
+
+#[test]
+fn split_waits_for_held_notes() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let policy = SplitPolicy {
+        pause: Duration::from_secs(2),
+        ..SplitPolicy::default()
+    };
+    let source =
+        ScriptedSource::new(vec!["Keys".to_string()], note(0.0, 60, 10.0).to_vec()).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .policy(policy)
+        .build();
+
+    run(&mut recorder, &clock, 10);
+    assert!(sink.file_names().is_empty());
+    assert_eq!(recorder.status().take_events, 1);
+    run(&mut recorder, &clock, 4);
+    assert_eq!(sink.file_names(), vec!["2024-01-02_03:04:18-4e-10s.mid"]);
+    assert_eq!(recorder.status().take_events, 0);
+}
+
+#[test]
+fn split_saves_take_and_continues() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let script = [note(0.0, 60, 3.0), note(0.5, 62, 0.2)].concat();
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .build();
+
+    // As on SIGUSR1, while a note is held.
+    run(&mut recorder, &clock, 2);
+    assert!(recorder.split());
+    assert_eq!(sink.file_names().len(), 1);
+    assert_eq!(recorder.status().take_events, 0);
+    run(&mut recorder, &clock, 2);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    assert_eq!(files.len(), 2);
+    let smf = Smf::parse(&files[0].1).unwrap();
+    let first = midi_events(&smf.tracks[0]);
+    assert_eq!(first.len(), 4);
+    // The held note is released at the end of the file and pressed again in the next one.
+    let smf = Smf::parse(&files[1].1).unwrap();
+    let second: Vec<_> = midi_events(&smf.tracks[0])
+        .into_iter()
+        .map(|e| e.1)
+        .collect();
+    assert_eq!(second, vec![first[0].1, first[3].1]);
+}
+
+/// Port that is unplugged for a while, it releases its held notes like `PortWatcher` does.
+struct UnpluggedPort {
+    script: ScriptedSource,
+    /// Seconds since the start when the port is unplugged and plugged back in.
+    unplugged: (u64, u64),
+    start: Option<Instant>,
+    connected: bool,
+}
+
+impl MidiSource for UnpluggedPort {
+    fn poll(&mut self, session: &SharedSession, now: Instant) -> Result<(), Box<dyn Error>> {
+        let elapsed = now.duration_since(*self.start.get_or_insert(now));
+        let connected = !(self.unplugged.0..self.unplugged.1).contains(&elapsed.as_secs());
+        if self.connected && !connected {
+            let timestamp = elapsed.as_micros() as u64;
+            session.lock().unwrap().release_held(0, timestamp);
+        }
+        self.connected = connected;
+        if connected {
+            self.script.poll(session, now)?;
+        }
+        Ok(())
+    }
+
+    fn connected_count(&self) -> usize {
+        self.connected as usize
+    }
+
+    fn input_count(&self) -> usize {
+        1
+    }
+}
+
+#[test]
+fn unplugged_port_releases_held_notes() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let policy = SplitPolicy {
+        pause: Duration::from_secs(2),
+        ..SplitPolicy::default()
+    };
+    // The Note Off of the first note is lost with the port.
+    let script = [&[event(0.0, &[0x90, 60, 100])][..], &note(10.0, 62, 0.5)].concat();
+    let source = UnpluggedPort {
+        script: ScriptedSource::new(vec!["Keys".to_string()], script).unwrap(),
+        unplugged: (1, 8),
+        start: None,
+        connected: false,
+    };
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .policy(policy)
+        .build();
+
+    run(&mut recorder, &clock, 2);
+    assert_eq!(recorder.status().connected_inputs, 0);
+    run(&mut recorder, &clock, 4);
+    assert_eq!(sink.file_names(), vec!["2024-01-02_03:04:09-4e-1s.mid"]);
+    run(&mut recorder, &clock, 6);
+    assert_eq!(recorder.status().connected_inputs, 1);
+    run(&mut recorder, &clock, 4);
+
+    let files = sink.files.lock().unwrap();
+    assert_eq!(files.len(), 2);
+    let smf = Smf::parse(&files[0].1).unwrap();
+    let events = midi_events(&smf.tracks[0]);
+    assert_eq!(events.len(), 2);
+    // Released when the port was unplugged, one second at 1000 ticks per beat and 120 BPM.
+    assert_eq!(events[1].0, 2000);
+    let smf = Smf::parse(&files[1].1).unwrap();
+    assert_eq!(midi_events(&smf.tracks[0]).len(), 2);
+}
+
+#[test]
+fn short_takes_are_discarded() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let policy = SplitPolicy {
+        min_events: 3,
+        ..SplitPolicy::default()
+    };
+    let script = [note(0.0, 60, 0.1), note(30.0, 60, 0.1), note(31.0, 62, 0.1)].concat();
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .policy(policy)
+        .build();
+
+    run(&mut recorder, &clock, 35);
+    assert!(recorder.finish());
+    assert_eq!(sink.file_names(), vec!["2024-01-02_03:04:40-6e-2s.mid"]);
+}
+
+#[test]
+fn archived_file_replays_the_same_events() {
+    let directory = temp_directory("replay");
+    let clock = clock();
+    let mut script = [note(0.0, 60, 0.25), note(0.5, 64, 0.25)].concat();
+    script.push(ScriptedEvent {
+        timestamp: 1_000_000,
+        track: 1,
+        bytes: vec![0xF0, 0x41, 0x10, 0x42, 0xF7],
+    });
+    script.push(ScriptedEvent {
+        timestamp: 1_500_000,
+        track: 1,
+        bytes: vec![0x99, 36, 90],
+    });
+    let source = ScriptedSource::new(vec!["Keys".to_string(), "Pads".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, Archive::new(directory.clone(), None))
+        .clock(clock.clone())
+        .journal(directory.clone())
+        .build();
+    run(&mut recorder, &clock, 5);
+    assert!(recorder.finish());
+
+    let path = directory.join("2024/1/2/2024-01-02_03:04:10-11e-2s.mid");
+    let original = fs::read(&path).unwrap();
+    // The journal is removed once the take is saved.
+    assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+    let clock = ManualClock::new(Local::now());
+    let sink = MemorySink::default();
+    let source = ScriptedSource::from_smf(&original).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .build();
+    run(&mut recorder, &clock, 3);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    assert_eq!(files.len(), 1);
+    let original = Smf::parse(&original).unwrap();
+    let replayed = Smf::parse(&files[0].1).unwrap();
+    assert_eq!(replayed.tracks, original.tracks);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn smf_tempo_changes_are_replayed() {
+    use midly::num::{u15, u24, u28, u4, u7};
+    use midly::{Format, Header, MidiMessage, Timing, TrackEvent};
+
+    let event = |delta: u32, kind| TrackEvent {
+        delta: u28::from(delta),
+        kind,
+    };
+    let note_on = TrackEventKind::Midi {
+        channel: u4::from(0),
+        message: MidiMessage::NoteOn {
+            key: u7::from(60),
+            vel: u7::from(100),
+        },
+    };
+    let tempo = |usec_per_beat| TrackEventKind::Meta(MetaMessage::Tempo(u24::from(usec_per_beat)));
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::from(100)),
+    ));
+    smf.tracks = vec![
+        vec![
+            event(0, tempo(1_000_000)),
+            event(200, tempo(250_000)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ],
+        vec![
+            event(100, note_on),
+            event(200, note_on),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ],
+    ];
+    let mut data = Vec::new();
+    smf.write(&mut data).unwrap();
+
+    let clock = clock();
+    let sink = MemorySink::default();
+    let source = ScriptedSource::from_smf(&data).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .build();
+    run(&mut recorder, &clock, 5);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let recorded = Smf::parse(&files[0].1).unwrap();
+    assert_eq!(recorded.tracks.len(), 1);
+    assert_eq!(
+        recorded.tracks[0][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Track 2"))
+    );
+    // 1 s per beat for the first 2 beats, then 0.25 s per beat: the notes are at 1 s and
+    // 2.25 s. Recorded at 500 µs per tick, the held note is released at the end.
+    let ticks: Vec<u64> = midi_events(&recorded.tracks[0])
+        .iter()
+        .map(|e| e.0)
+        .collect();
+    assert_eq!(ticks, vec![0, 2500, 2500]);
+}
+
+#[test]
+fn midi_clock_sets_tempo_and_beat_grid() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let at = |timestamp: u64, bytes: &[u8]| ScriptedEvent {
+        timestamp,
+        track: 0,
+        bytes: bytes.to_vec(),
+    };
+    // 4 beats at 100 BPM, then 4 beats at 125 BPM.
+    let beats: Vec<u64> = (0..4)
+        .map(|beat| beat * 600_000)
+        .chain((0..=4).map(|beat| 2_400_000 + beat * 480_000))
+        .collect();
+    let mut script = Vec::new();
+    for beat in beats.windows(2) {
+        let pulse = (beat[1] - beat[0]) / 24;
+        script.extend((0..24).map(|n| at(beat[0] + n * pulse, &[0xF8])));
+    }
+    // Notes on the beats, released half a clock pulse later.
+    for &beat in &beats[..8] {
+        let pulse = if beat < 2_400_000 { 25_000 } else { 20_000 };
+        script.push(at(beat, &[0x90, 60, 100]));
+        script.push(at(beat + pulse / 2, &[0x80, 60, 0]));
+    }
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .build();
+
+    run(&mut recorder, &clock, 5);
+    assert!(recorder.finish());
+
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[0].1).unwrap();
+    let mut tick = 0;
+    let mut tempo_changes = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Tempo(usec_per_beat)) = event.kind {
+            tempo_changes.push((tick, usec_per_beat.as_int()));
+        }
+    }
+    assert_eq!(tempo_changes, vec![(0, 600_000), (4000, 480_000)]);
+    // Between pulses ticks follow the smoothed pulse interval, which only settles after
+    // the first pulse and after a tempo change.
+    let ticks: Vec<u64> = midi_events(&smf.tracks[0]).iter().map(|e| e.0).collect();
+    assert_eq!(
+        ticks,
+        vec![
+            0, 25, 1000, 1020, 2000, 2020, 3000, 3020, 4000, 4016, 5000, 5020, 6000, 6020, 7000,
+            7020
+        ]
+    );
+}
+
+#[test]
+fn takes_follow_transport() {
+    let clock = clock();
+    let sink = MemorySink::default();
+    let policy = SplitPolicy {
+        follow_transport: true,
+        ..SplitPolicy::default()
+    };
+    let script = [
+        &note(0.0, 60, 0.1)[..],
+        &[event(1.0, &[0xFA])],
+        &note(1.0, 62, 0.1),
+        // A pause longer than the split policy allows, while the transport plays.
+        &note(12.0, 64, 0.1),
+        &[event(13.9, &[0x90, 65, 100]), event(14.0, &[0xFC])],
+        &[event(14.05, &[0x80, 65, 0])],
+    ]
+    .concat();
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, sink.clone())
+        .clock(clock.clone())
+        .policy(policy)
+        .build();
+
+    run(&mut recorder, &clock, 30);
+    assert!(recorder.finish());
+
+    assert_eq!(
+        sink.file_names(),
+        vec![
+            "2024-01-02_03:04:06-4e-1s.mid",
+            "2024-01-02_03:04:20-10e-14s.mid"
+        ]
+    );
+    let files = sink.files.lock().unwrap();
+    let smf = Smf::parse(&files[1].1).unwrap();
+    let mut tick = 0;
+    let mut markers = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Marker(text)) = event.kind {
+            markers.push((tick, text));
+        }
+    }
+    assert_eq!(markers, vec![(0, &b"Start"[..]), (26000, b"Stop")]);
+}
+
+#[test]
+fn controls_split_keep_and_discard_takes() {
+    let directory = temp_directory("controls");
+    let clock = clock();
+    let keys = |seconds| {
+        [
+            event(seconds, &[0x90, 21, 100]),
+            event(seconds, &[0x90, 22, 100]),
+            event(seconds + 0.1, &[0x80, 21, 0]),
+            event(seconds + 0.1, &[0x80, 22, 0]),
+        ]
+    };
+    let script = [
+        &note(0.0, 60, 0.1)[..],
+        // Keep and split the take in progress.
+        &[event(1.0, &[0xB0, 81, 127]), event(1.1, &[0xB0, 81, 0])],
+        &[event(2.0, &[0xB0, 80, 127]), event(2.1, &[0xB0, 80, 0])],
+        // Discard the take in progress.
+        &note(3.0, 62, 0.1),
+        &keys(4.0),
+        // Once saved on pause, keep this take.
+        &note(5.0, 64, 0.1),
+        &[event(16.0, &[0xB0, 81, 127]), event(16.1, &[0xB0, 81, 0])],
+        // And discard this one.
+        &note(18.0, 65, 0.1),
+        &keys(30.0),
+    ]
+    .concat();
+    let controls = ["split=cc80", "keep=cc81", "discard=note21+22"]
+        .iter()
+        .map(|c| c.parse().unwrap())
+        .collect();
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, Archive::new(directory.clone(), None))
+        .clock(clock.clone())
+        .controls(Controls::new(controls, true))
+        .build();
+    run(&mut recorder, &clock, 35);
+    assert!(recorder.finish());
+
+    let day = directory.join("2024/1/2");
+    let mut names: Vec<_> = fs::read_dir(&day)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "2024-01-02_03:04:07-5e-1s-keep.mid",
+            "2024-01-02_03:04:20-4e-1s-keep.mid"
+        ]
+    );
+    let data = fs::read(day.join(&names[0])).unwrap();
+    let smf = Smf::parse(&data).unwrap();
+    // Control events are left out, the keeper mark is where it was given.
+    assert_eq!(midi_events(&smf.tracks[0]).len(), 2);
+    let mut tick = 0;
+    let mut markers = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Marker(text)) = event.kind {
+            markers.push((tick, text));
+        }
+    }
+    assert_eq!(markers, vec![(2000, &b"Keep"[..])]);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn recorded_controls_do_not_start_takes() {
+    let directory = temp_directory("unfiltered-controls");
+    let clock = clock();
+    let script = [
+        &note(0.0, 60, 0.1)[..],
+        // Keep the take saved on pause.
+        &[event(20.0, &[0xB0, 81, 127]), event(20.1, &[0xB0, 81, 0])],
+        // Discard a take, the keys are released after that.
+        &note(30.0, 62, 0.1),
+        &[
+            event(31.0, &[0x90, 21, 100]),
+            event(31.0, &[0x90, 22, 100]),
+            event(31.5, &[0x80, 21, 0]),
+            event(31.5, &[0x80, 22, 0]),
+        ],
+        // Nothing to split.
+        &[event(45.0, &[0xB0, 80, 127]), event(45.1, &[0xB0, 80, 0])],
+    ]
+    .concat();
+    let controls = ["split=cc80", "keep=cc81", "discard=note21+22"]
+        .iter()
+        .map(|c| c.parse().unwrap())
+        .collect();
+    let source = ScriptedSource::new(vec!["Keys".to_string()], script).unwrap();
+    let mut recorder = Recorder::builder(source, Archive::new(directory.clone(), None))
+        .clock(clock.clone())
+        .controls(Controls::new(controls, false))
+        .build();
+    run(&mut recorder, &clock, 60);
+    assert!(recorder.finish());
+
+    let names: Vec<_> = fs::read_dir(directory.join("2024/1/2"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, vec!["2024-01-02_03:04:15-4e-1s-keep.mid"]);
+    fs::remove_dir_all(&directory).unwrap();
+}